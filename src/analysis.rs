@@ -0,0 +1,132 @@
+use crate::{error::ParseError, microcode::{Instruction, State, state_vec_to_string}, pla::{instructions_to_terms, merge_terms, Term}, Config};
+
+/// Inputs matched by operations of two different instructions, the PLA ORs both microcode sets there.
+#[derive(Debug, Clone)]
+pub struct Overlap {
+    pub first: usize,
+    pub second: usize,
+    pub inputs: Vec<State>,
+    pub first_micro_operations: Vec<u64>,
+    pub second_micro_operations: Vec<u64>
+}
+
+impl Overlap {
+    pub fn report(&self, instructions: &[Instruction], config: &Config) -> String {
+        format!(
+            "Instructions {} and {} overlap on {}: {} | {}",
+            instructions[self.first].header(config),
+            instructions[self.second].header(config),
+            format_inputs(&self.inputs, config),
+            micro_operation_names(&self.first_micro_operations, config).join(" "),
+            micro_operation_names(&self.second_micro_operations, config).join(" ")
+        )
+    }
+}
+
+/// Splits a row's inputs back into named opcode fields, counter and flags, the flags may be left out.
+/// Named flags are written as conditions and only if the row depends on them.
+pub fn format_inputs(inputs: &[State], config: &Config) -> String {
+    let mut parts = vec![];
+    let mut offset = 0;
+    for (name, length) in &config.opcodes {
+        parts.push(format!("{}={}", name, state_vec_to_string(&inputs[offset..offset + *length as usize])));
+        offset += *length as usize;
+    }
+    parts.push(format!("counter={}", state_vec_to_string(&inputs[offset..offset + config.counter_bit_length as usize])));
+    offset += config.counter_bit_length as usize;
+    if offset < inputs.len() && config.flags.is_empty() {
+        parts.push(format!("flags={}", state_vec_to_string(&inputs[offset..])));
+    } else if offset < inputs.len() {
+        for (name, state) in config.flags.iter().zip(&inputs[offset..]) {
+            if *state == State::Any {continue;}
+            parts.push(format!("?{}={}", name, state_vec_to_string(&[*state])));
+        }
+    }
+    parts.join(" ")
+}
+
+/// Names of the microcodes mapped to the given output columns, the alphabetically first one if several share a column.
+/// Field columns are grouped into `FIELD=VALUE`, fields set to zero are left out.
+pub fn micro_operation_names(micro_operations: &[u64], config: &Config) -> Vec<String> {
+    let mut field_columns = vec![];
+    let mut field_names = vec![];
+    for field in &config.fields {
+        field_columns.extend(field.index..field.index + field.length);
+        let value: u64 = (0..field.length.min(64)).filter(|b| micro_operations.contains(&(field.index + b))).map(|b| 1 << b).sum();
+        if value == 0 {continue;}
+        let value_name = field.values.iter()
+            .filter(|(_, f)| **f == value)
+            .map(|(name, _)| name.clone())
+            .min()
+            .unwrap_or_else(|| value.to_string());
+        field_names.push(format!("{}={}", field.name, value_name));
+    }
+
+    let mut names: Vec<String> = micro_operations.iter().filter(|f| !field_columns.contains(f)).map(|index| {
+        config.microcode_map.iter()
+            .filter(|(_, value)| **value == *index as i64)
+            .map(|(name, _)| name.clone())
+            .min()
+            .unwrap_or_else(|| index.to_string())
+    }).collect();
+    names.append(&mut field_names);
+    names
+}
+
+fn intersect(first: &Term, second: &Term) -> Option<Vec<State>> {
+    first.inputs.iter().zip(&second.inputs).map(|(a, b)| match (a, b) {
+        (State::Any, other) | (other, State::Any) => Some(*other),
+        (a, b) if a == b => Some(*a),
+        _ => None
+    }).collect()
+}
+
+/// Every pair of operations from instructions with different opcode patterns that can fire on the same input.
+pub fn find_overlaps(instructions: &[Instruction], config: &Config) -> Result<Vec<Overlap>, ParseError> {
+    let terms = instructions_to_terms(instructions, config)?;
+    let mut overlaps = vec![];
+    for (i, first) in terms.iter().enumerate() {
+        for second in &terms[i + 1..] {
+            if first.instruction == second.instruction {continue;}
+            if instructions[first.instruction].opcodes == instructions[second.instruction].opcodes {continue;}
+            if first.micro_operations.is_empty() || second.micro_operations.is_empty() {continue;}
+            if let Some(inputs) = intersect(first, second) {
+                overlaps.push(Overlap {
+                    first: first.instruction,
+                    second: second.instruction,
+                    inputs,
+                    first_micro_operations: first.micro_operations.clone(),
+                    second_micro_operations: second.micro_operations.clone()
+                });
+            }
+        }
+    }
+    Ok(overlaps)
+}
+
+/// Parts of `cube` not matched by `other`, as disjoint cubes.
+fn sharp(cube: &[State], other: &[State]) -> Vec<Vec<State>> {
+    let disjoint = cube.iter().zip(other).any(|(a, b)| *a != State::Any && *b != State::Any && a != b);
+    if disjoint {return vec![cube.to_vec()];}
+    let mut result = vec![];
+    let mut remainder = cube.to_vec();
+    for (i, state) in other.iter().enumerate() {
+        if *state == State::Any || remainder[i] != State::Any {continue;}
+        let mut part = remainder.clone();
+        part[i] = if *state == State::True { State::False } else { State::True };
+        result.push(part);
+        remainder[i] = *state;
+    }
+    result
+}
+
+/// Opcode and counter bits no operation fires on, flag conditions are ignored.
+pub fn find_uncovered(instructions: &[Instruction], config: &Config) -> Result<Vec<Vec<State>>, ParseError> {
+    let length = (config.opcodes.iter().map(|f| f.1).sum::<u64>() + config.counter_bit_length) as usize;
+    let mut uncovered = vec![vec![State::Any; length]];
+    for term in instructions_to_terms(instructions, config)? {
+        uncovered = uncovered.iter().flat_map(|f| sharp(f, &term.inputs[..length])).collect();
+    }
+    let terms = uncovered.into_iter().map(|inputs| Term { inputs, micro_operations: vec![], instruction: 0 }).collect();
+    Ok(merge_terms(terms).into_iter().map(|f| f.inputs).collect())
+}
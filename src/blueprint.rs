@@ -1,11 +1,33 @@
 use crate::ink::InkLayer;
-use zstd::Encoder;
+use zstd::{Encoder, decode_all};
 use base64::engine::{general_purpose::STANDARD, Engine};
 use sha1_smol::Sha1;
 use std::io::Write;
-use crate::error::Error;
+use crate::error::{Error, BlueprintError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+	Logic,
+	DecorationOn,
+	DecorationOff
+}
+
+impl Layer {
+	pub fn id(self) -> u32 {
+		match self {
+			Layer::Logic => 0,
+			Layer::DecorationOn => 1,
+			Layer::DecorationOff => 2
+		}
+	}
+}
 
 pub fn generate_logic_blueprint(ink_buffer: &InkLayer, width: u32, height: u32) -> Result<String, Error> {
+	generate_layered_blueprint(&[(Layer::Logic, ink_buffer)], width, height)
+}
+
+/// Writes one block per layer, every layer has to be `width * height` inks.
+pub fn generate_layered_blueprint(layers: &[(Layer, &InkLayer)], width: u32, height: u32) -> Result<String, Error> {
 	let mut blueprint = String::new();
 	blueprint.push_str("VCB+AAAA");
 	let mut blueprint_chunk: Vec<u8> = vec![];
@@ -13,15 +35,20 @@ pub fn generate_logic_blueprint(ink_buffer: &InkLayer, width: u32, height: u32)
 	blueprint_chunk.append(&mut width.to_be_bytes().to_vec());
 	blueprint_chunk.append(&mut height.to_be_bytes().to_vec());
 
-	let mut compressed_buffer: Vec<u8> = vec![];
-	let mut encoder = Encoder::new(&mut compressed_buffer, 22)?;
-	encoder.write_all(&ink_buffer.to_be_bytes())?;
-	encoder.finish()?;
+	for (layer, ink_buffer) in layers {
+		if ink_buffer.ink_buffer.len() != width as usize * height as usize {
+			return Err(BlueprintError::LayerSize(layer.id()).into());
+		}
+		let mut compressed_buffer: Vec<u8> = vec![];
+		let mut encoder = Encoder::new(&mut compressed_buffer, 22)?;
+		encoder.write_all(&ink_buffer.to_be_bytes())?;
+		encoder.finish()?;
 
-	blueprint_chunk.append(&mut (compressed_buffer.len() as u32 + 12).to_be_bytes().to_vec());
-	blueprint_chunk.append(&mut vec![0,0,0,0]);
-	blueprint_chunk.append(&mut (ink_buffer.to_be_bytes().len() as u32).to_be_bytes().to_vec());
-	blueprint_chunk.append(&mut compressed_buffer);
+		blueprint_chunk.append(&mut (compressed_buffer.len() as u32 + 12).to_be_bytes().to_vec());
+		blueprint_chunk.append(&mut layer.id().to_be_bytes().to_vec());
+		blueprint_chunk.append(&mut (ink_buffer.to_be_bytes().len() as u32).to_be_bytes().to_vec());
+		blueprint_chunk.append(&mut compressed_buffer);
+	}
 	
 	let base64_blueprint_chunk = STANDARD.encode(&blueprint_chunk);
 
@@ -29,8 +56,78 @@ pub fn generate_logic_blueprint(ink_buffer: &InkLayer, width: u32, height: u32)
 	hasher.update(base64_blueprint_chunk.as_bytes());
 	let hash = &hasher.digest().bytes()[0..6];
 
-	blueprint.push_str(&STANDARD.encode(&hash));
+	blueprint.push_str(&STANDARD.encode(hash));
 	blueprint.push_str(&base64_blueprint_chunk);
 
 	Ok(blueprint)
-}
\ No newline at end of file
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, BlueprintError> {
+	let slice = bytes.get(offset..offset + 4).ok_or(BlueprintError::Header)?;
+	Ok(u32::from_be_bytes([slice[0], slice[1], slice[2], slice[3]]))
+}
+
+/// Inverse of `generate_logic_blueprint`, returns the logic layer along with its width and height.
+pub fn decode_logic_blueprint(blueprint: &str) -> Result<(InkLayer, u32, u32), Error> {
+	decode_blueprint_layer(blueprint, Layer::Logic)
+}
+
+pub fn decode_blueprint_layer(blueprint: &str, layer: Layer) -> Result<(InkLayer, u32, u32), Error> {
+	let blueprint = blueprint.trim();
+	let body = blueprint.strip_prefix("VCB+").ok_or(BlueprintError::Prefix)?;
+	let bytes = STANDARD.decode(body).map_err(|_| BlueprintError::Encoding)?;
+	if bytes.len() < 17 || body.len() < 12 {return Err(BlueprintError::Header.into());}
+
+	// the checksum covers the base64 text following the version and hash bytes
+	let mut hasher = Sha1::new();
+	hasher.update(&body.as_bytes()[12..]);
+	if hasher.digest().bytes()[0..6] != bytes[3..9] {return Err(BlueprintError::Checksum.into());}
+
+	let width = read_u32(&bytes, 9)?;
+	let height = read_u32(&bytes, 13)?;
+
+	let mut offset = 17;
+	while offset < bytes.len() {
+		let block_size = read_u32(&bytes, offset)? as usize;
+		let layer_id = read_u32(&bytes, offset + 4)?;
+		let decompressed_size = read_u32(&bytes, offset + 8)? as usize;
+		if block_size < 12 {return Err(BlueprintError::Header.into());}
+		let compressed = bytes.get(offset + 12..offset + block_size).ok_or(BlueprintError::Header)?;
+
+		if layer_id == layer.id() {
+			let decompressed = decode_all(compressed)?;
+			if decompressed.len() != decompressed_size || decompressed.len() != width as usize * height as usize * 4 {
+				return Err(BlueprintError::LayerSize(layer_id).into());
+			}
+			return Ok((InkLayer::from_be_bytes(&decompressed), width, height));
+		}
+		offset += block_size;
+	}
+
+	Err(BlueprintError::MissingLayer(layer.id()).into())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::ink::{Ink, RGBA};
+	use super::*;
+
+	#[test]
+	fn decode_reverses_generate() {
+		let (width, height) = (5, 3);
+		let inks = [Ink::CROSS, Ink::READ, Ink::WRITE, RGBA::new(0, 0, 0, 0), Ink::TC_GRAY];
+		let layer = InkLayer::new((0..width * height).map(|f| inks[f as usize % inks.len()]).collect());
+		let blueprint = generate_logic_blueprint(&layer, width, height).unwrap();
+
+		let (decoded, decoded_width, decoded_height) = decode_logic_blueprint(&blueprint).unwrap();
+		assert_eq!(decoded.ink_buffer, layer.ink_buffer);
+		assert_eq!((decoded_width, decoded_height), (width, height));
+
+		// first character of the base64 checksum
+		let mut corrupted = blueprint.clone();
+		let replacement = if &blueprint[8..9] == "A" { "B" } else { "A" };
+		corrupted.replace_range(8..9, replacement);
+		let error = decode_logic_blueprint(&corrupted).unwrap_err();
+		assert!(matches!(error.downcast_ref::<BlueprintError>(), Some(BlueprintError::Checksum)));
+	}
+}
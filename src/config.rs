@@ -0,0 +1,344 @@
+use std::{collections::{BTreeMap, HashMap}, fs::File, io::BufReader, path::Path};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use serde_path_to_error::Segment;
+use crate::{error::{ConfigError, ConfigErrors, Error}, microcode::{parse_opcode_literal, State}};
+
+/// Template written by `init`.
+pub const DEFAULT_CONFIG: &str = include_str!("default_config.json");
+
+/// Widest counter accepted, every step of it is visited when simulating or checking coverage.
+const MAX_COUNTER_BIT_LENGTH: u64 = 16;
+
+/// Output columns a PLA may have, microcode indices and fields have to stay below it.
+const MAX_COLUMNS: u64 = 1024;
+
+/// Group of output columns written as one binary number, bit `b` of the value goes to column `index + b`.
+#[derive(Deserialize)]
+pub struct Field {
+	pub name: String,
+	pub index: u64,
+	pub length: u64,
+	#[serde(default)]
+	pub values: HashMap<String, u64>
+}
+
+pub struct Config {
+	pub opcodes: Vec<(String, u64)>,
+	/// Named patterns for each opcode, usable as `[OPCODE=NAME]`.
+	pub opcode_values: HashMap<String, HashMap<String, Vec<State>>>,
+	pub microcode_map: HashMap<String, i64>,
+	pub counter_bit_length: u64,
+	pub flags_bit_length: u64,
+	/// Flag names in column order, may be empty if the flags are only used through negative microcode indices.
+	pub flags: Vec<String>,
+	pub fields: Vec<Field>
+}
+
+/// Opcode value as a number or a literal as written in a header.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "expected a number or an opcode literal")]
+enum OpcodeValue {
+	Number(u64),
+	Literal(String)
+}
+
+#[derive(Deserialize)]
+struct OpcodeSerde {
+	name: String,
+	length: u64,
+	#[serde(default)]
+	values: BTreeMap<String, OpcodeValue>
+}
+
+/// Config file as written, `validate` checks it and turns it into a `Config`.
+#[derive(Deserialize)]
+struct ConfigSerde {
+	opcodes: Vec<OpcodeSerde>,
+	counter_bit_length: u64,
+	flags_bit_length: Option<u64>,
+	#[serde(default)]
+	flags: Vec<String>,
+	microcodes: BTreeMap<String, i64>,
+	#[serde(default)]
+	fields: Vec<Field>
+}
+
+/// Escapes an object key for use in a JSON pointer.
+fn escape(key: &str) -> String {
+	key.replace('~', "~0").replace('/', "~1")
+}
+
+fn pointer(path: &serde_path_to_error::Path) -> String {
+	path.iter().map(|segment| match segment {
+		Segment::Seq { index } => format!("/{}", index),
+		Segment::Map { key } => format!("/{}", escape(key)),
+		Segment::Enum { variant } => format!("/{}", escape(variant)),
+		Segment::Unknown => String::new()
+	}).collect()
+}
+
+impl ConfigSerde {
+	/// Collects every problem instead of stopping at the first, each with the pointer to the offending value.
+	fn validate(self) -> Result<Config, Vec<(String, ConfigError)>> {
+		let mut errors = vec![];
+
+		let flags_bit_length = match self.flags_bit_length {
+			Some(flags_bit_length) => flags_bit_length,
+			None if !self.flags.is_empty() => self.flags.len() as u64,
+			None => {
+				errors.push((String::new(), ConfigError::Deserialize("missing field `flags_bit_length`".to_owned())));
+				0
+			}
+		};
+		if !self.flags.is_empty() && self.flags.len() as u64 != flags_bit_length {
+			errors.push(("/flags".to_owned(), ConfigError::FlagCount(self.flags.len(), flags_bit_length)));
+		}
+		for (i, flag) in self.flags.iter().enumerate() {
+			if self.flags[..i].contains(flag) {
+				errors.push((format!("/flags/{}", i), ConfigError::DuplicateFlag(flag.clone())));
+			}
+		}
+
+		if self.counter_bit_length > MAX_COUNTER_BIT_LENGTH {
+			errors.push(("/counter_bit_length".to_owned(), ConfigError::CounterLength(self.counter_bit_length, MAX_COUNTER_BIT_LENGTH)));
+		}
+
+		let mut opcodes: Vec<(String, u64)> = vec![];
+		let mut opcode_values = HashMap::new();
+		for (i, opcode) in self.opcodes.into_iter().enumerate() {
+			if opcodes.iter().any(|f| f.0 == opcode.name) {
+				errors.push((format!("/opcodes/{}/name", i), ConfigError::DuplicateOpcode(opcode.name.clone())));
+			}
+			if opcode.length == 0 {
+				errors.push((format!("/opcodes/{}/length", i), ConfigError::ZeroLength(opcode.name.clone())));
+			}
+			let mut values = HashMap::new();
+			for (key, value) in opcode.values {
+				let literal = match value {
+					OpcodeValue::Number(number) => format!("0b{:b}", number),
+					OpcodeValue::Literal(literal) => literal
+				};
+				match parse_opcode_literal(&literal, &opcode.name, opcode.length) {
+					Ok(pattern) => {values.insert(key, pattern);},
+					Err(e) => errors.push((format!("/opcodes/{}/values/{}", i, escape(&key)), ConfigError::OpcodeValue(e)))
+				}
+			}
+			opcode_values.entry(opcode.name.clone()).or_insert(values);
+			opcodes.push((opcode.name, opcode.length));
+		}
+
+		// flag indices and output columns with the microcode using them
+		let mut users: HashMap<i64, &String> = HashMap::new();
+		for (name, index) in &self.microcodes {
+			let pointer = format!("/microcodes/{}", escape(name));
+			if let Some(other) = users.get(index) {
+				errors.push((pointer, ConfigError::DuplicateIndex(name.clone(), *index, (*other).clone())));
+				continue;
+			}
+			users.insert(*index, name);
+			if *index < 0 && index.unsigned_abs() > flags_bit_length * 2 {
+				errors.push((pointer, ConfigError::FlagIndex(name.clone(), *index)));
+			} else if *index >= MAX_COLUMNS as i64 {
+				errors.push((pointer, ConfigError::ColumnRange(name.clone(), MAX_COLUMNS)));
+			}
+		}
+		// columns `start..end` taken by microcodes and the fields before
+		let mut ranges: Vec<(u64, u64, &String)> = users.iter().filter(|f| *f.0 >= 0).map(|(index, name)| (*index as u64, *index as u64 + 1, *name)).collect();
+		ranges.sort();
+		for (i, field) in self.fields.iter().enumerate() {
+			if self.fields[..i].iter().any(|f| f.name == field.name) {
+				errors.push((format!("/fields/{}/name", i), ConfigError::DuplicateField(field.name.clone())));
+			}
+			if self.microcodes.contains_key(&field.name) {
+				errors.push((format!("/fields/{}/name", i), ConfigError::FieldName(field.name.clone())));
+			}
+			let mut too_wide: Vec<&String> = field.values.iter().filter(|f| field.length < 64 && *f.1 >> field.length != 0).map(|f| f.0).collect();
+			too_wide.sort();
+			for key in too_wide {
+				errors.push((format!("/fields/{}/values/{}", i, escape(key)), ConfigError::FieldValue(field.name.clone(), key.clone(), field.length)));
+			}
+			if field.length == 0 {
+				errors.push((format!("/fields/{}/length", i), ConfigError::ZeroLength(field.name.clone())));
+				continue;
+			}
+			let Some(end) = field.index.checked_add(field.length).filter(|f| *f <= MAX_COLUMNS) else {
+				errors.push((format!("/fields/{}", i), ConfigError::ColumnRange(field.name.clone(), MAX_COLUMNS)));
+				continue;
+			};
+			let collision = ranges.iter()
+				.filter(|f| f.0 < end && field.index < f.1)
+				.map(|f| (f.0.max(field.index), f.2))
+				.min();
+			if let Some((column, other)) = collision {
+				errors.push((format!("/fields/{}", i), ConfigError::ColumnCollision(field.name.clone(), column, other.clone())));
+			}
+			ranges.push((field.index, end, &field.name));
+		}
+
+		if !errors.is_empty() {
+			return Err(errors);
+		}
+		Ok(Config {
+			opcodes,
+			opcode_values,
+			microcode_map: self.microcodes.into_iter().collect(),
+			counter_bit_length: self.counter_bit_length,
+			flags_bit_length,
+			flags: self.flags,
+			fields: self.fields
+		})
+	}
+}
+
+impl TryFrom<Value> for Config {
+	type Error = Error;
+	fn try_from(value: Value) -> Result<Self, Self::Error> {
+		Config::deserialize_checked(value).map_err(|errors| ConfigErrors { path: None, errors }.into())
+	}
+}
+
+impl Config {
+	/// Highest output column used by a microcode or field.
+	pub fn max_micro_operation_index(&self) -> Option<u64> {
+		let microcodes = self.microcode_map.values().filter(|f| **f >= 0).map(|f| *f as u64);
+		let fields = self.fields.iter().filter(|f| f.length > 0).map(|f| f.index + f.length - 1);
+		microcodes.chain(fields).max()
+	}
+
+	fn deserialize_checked<'de>(deserializer: impl Deserializer<'de, Error = serde_json::Error>) -> Result<Config, Vec<(String, ConfigError)>> {
+		let config_serde: ConfigSerde = serde_path_to_error::deserialize(deserializer)
+			.map_err(|e| vec![(pointer(e.path()), ConfigError::Deserialize(e.into_inner().to_string()))])?;
+		config_serde.validate()
+	}
+
+	pub fn load(path: &Path) -> Result<Config, Error> {
+		let config_file = File::open(path).map_err(|e| format!("Couldn't open config '{}': {}", path.display(), e))?;
+		let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(config_file));
+		Config::deserialize_checked(&mut deserializer)
+			.and_then(|config| deserializer.end().map(|_| config).map_err(|e| vec![(String::new(), ConfigError::Deserialize(e.to_string()))]))
+			.map_err(|errors| ConfigErrors { path: Some(path.to_owned()), errors }.into())
+	}
+
+	/// JSON Schema of the config file, for completion and checking in editors.
+	pub fn schema() -> Value {
+		json!({
+			"$schema": "http://json-schema.org/draft-07/schema#",
+			"title": "vcb_mips_tools config",
+			"type": "object",
+			"required": ["opcodes", "counter_bit_length", "microcodes"],
+			"properties": {
+				"opcodes": {
+					"description": "Opcode inputs of the PLA, the first bit of the first opcode is the most significant",
+					"type": "array",
+					"items": {
+						"type": "object",
+						"required": ["name", "length"],
+						"properties": {
+							"name": {"type": "string"},
+							"length": {"type": "integer", "minimum": 1},
+							"values": {
+								"description": "Named patterns usable as [NAME=VALUE], a number or a literal like 0b10xx, 0x1# or 10##",
+								"type": "object",
+								"additionalProperties": {"type": ["integer", "string"], "minimum": 0}
+							}
+						}
+					}
+				},
+				"counter_bit_length": {"type": "integer", "minimum": 0, "maximum": MAX_COUNTER_BIT_LENGTH},
+				"flags_bit_length": {
+					"description": "Number of flag inputs, defaults to the number of flags",
+					"type": "integer",
+					"minimum": 0
+				},
+				"flags": {
+					"description": "Flag names in column order, usable as ?NAME=0 or ?NAME=1",
+					"type": "array",
+					"items": {"type": "string"}
+				},
+				"microcodes": {
+					"description": "Output column of every microcode, negative indices are legacy flag conditions",
+					"type": "object",
+					"additionalProperties": {"type": "integer", "maximum": MAX_COLUMNS - 1}
+				},
+				"fields": {
+					"description": "Groups of output columns written as one binary number with NAME=VALUE",
+					"type": "array",
+					"items": {
+						"type": "object",
+						"required": ["name", "index", "length"],
+						"properties": {
+							"name": {"type": "string"},
+							"index": {"type": "integer", "minimum": 0, "maximum": MAX_COLUMNS - 1},
+							"length": {"type": "integer", "minimum": 1, "maximum": MAX_COLUMNS},
+							"values": {"type": "object", "additionalProperties": {"type": "integer", "minimum": 0}}
+						}
+					}
+				}
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn validation_errors(value: Value) -> Vec<(String, String)> {
+		match Config::deserialize_checked(value) {
+			Ok(_) => vec![],
+			Err(errors) => errors.into_iter().map(|(pointer, error)| (pointer, error.to_string())).collect()
+		}
+	}
+
+	#[test]
+	fn validate_collects_every_error_with_its_pointer() {
+		let errors = validation_errors(json!({
+			"opcodes": [{"name": "OPCODE", "length": 4, "values": {"BAD": "0b11111", "ADD": 3}}, {"name": "OPCODE", "length": 0}],
+			"counter_bit_length": 3,
+			"flags": ["Z", "Z"],
+			"microcodes": {"A": 0, "B": 0, "C/D": 0, "F": 3, "FAR": -9},
+			"fields": [
+				{"name": "F", "index": 2, "length": 2, "values": {"BIG": 9, "OK": 3}},
+				{"name": "F", "index": 8, "length": 1},
+				{"name": "H", "index": 18446744073709551615u64, "length": 1},
+				{"name": "I", "index": 9, "length": 3000000000000u64},
+				{"name": "J", "index": 10, "length": 0}
+			]
+		}));
+		let pointers: Vec<&str> = errors.iter().map(|f| f.0.as_str()).collect();
+		assert_eq!(pointers, [
+			"/flags/1",
+			"/opcodes/0/values/BAD", "/opcodes/1/name", "/opcodes/1/length",
+			"/microcodes/B", "/microcodes/C~1D", "/microcodes/FAR",
+			"/fields/0/name", "/fields/0/values/BIG", "/fields/0",
+			"/fields/1/name", "/fields/1/name",
+			"/fields/2", "/fields/3", "/fields/4/length"
+		]);
+		assert_eq!(errors[4].1, "Microcode 'B' has index 0, which 'A' already uses");
+		assert_eq!(errors[8].1, "Value 'BIG' doesn't fit in the 2 bits of field 'F'");
+		assert_eq!(errors[9].1, "Field 'F' covers column 3, which 'F' already uses");
+	}
+
+	#[test]
+	fn deserialize_errors_point_at_the_value() {
+		let errors = validation_errors(json!({"opcodes": [{"name": "OPCODE", "length": "4"}], "counter_bit_length": 3, "microcodes": {}}));
+		assert_eq!(errors, [("/opcodes/0/length".to_owned(), "invalid type: string \"4\", expected u64".to_owned())]);
+		let errors = validation_errors(json!({"opcodes": [], "counter_bit_length": 3, "microcodes": {}}));
+		assert_eq!(errors, [(String::new(), "missing field `flags_bit_length`".to_owned())]);
+	}
+
+	#[test]
+	fn valid_config_loads() {
+		let config = Config::deserialize_checked(json!({
+			"opcodes": [{"name": "OPCODE", "length": 4, "values": {"ADD": 3, "ALU": "0b01xx"}}],
+			"counter_bit_length": 3,
+			"flags": ["Z", "C"],
+			"microcodes": {"A": 0, "IF_Z": -1},
+			"fields": [{"name": "ALU", "index": 1, "length": 3, "values": {"SUB": 7}}]
+		})).ok().unwrap();
+		assert_eq!(config.flags_bit_length, 2);
+		assert_eq!(config.opcode_values["OPCODE"]["ADD"], parse_opcode_literal("0011", "OPCODE", 4).unwrap());
+		assert_eq!(config.max_micro_operation_index(), Some(3));
+	}
+}
@@ -0,0 +1,110 @@
+use crate::{analysis::micro_operation_names, microcode::{Instruction, Operation, State, state_vec_to_string}, Config};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocFormat {
+    Markdown,
+    Html
+}
+
+/// Config opcode value names matching the instruction's opcodes exactly, joined by spaces.
+fn instruction_name(instruction: &Instruction, config: &Config) -> Option<String> {
+    let mut names = vec![];
+    for ((name, _), pattern) in config.opcodes.iter().zip(&instruction.opcodes) {
+        if pattern.iter().all(|f| *f == State::Any) {continue;}
+        let values = config.opcode_values.get(name)?;
+        let mut matching: Vec<&String> = values.iter().filter(|f| f.1 == pattern).map(|f| f.0).collect();
+        matching.sort();
+        names.push(matching.first()?.to_string());
+    }
+    (!names.is_empty()).then(|| names.join(" "))
+}
+
+fn first_step(counter: &[State]) -> u64 {
+    counter.iter().fold(0, |value, f| value << 1 | (*f == State::True) as u64)
+}
+
+/// A single step, a range if only the low bits are don't-cares, the bit pattern otherwise.
+fn format_step(counter: &[State]) -> String {
+    let fixed = counter.iter().take_while(|f| **f != State::Any).count();
+    if fixed == counter.len() {return first_step(counter).to_string();}
+    if counter[fixed..].iter().any(|f| *f != State::Any) {return state_vec_to_string(counter);}
+    if fixed == 0 {return "every".to_owned();}
+    let first = first_step(counter);
+    format!("{}-{}", first, first + (1 << (counter.len() - fixed)) - 1)
+}
+
+/// Flags the operation depends on as `NAME=BIT`, or the flag bits if the flags aren't named.
+fn format_condition(operation: &Operation, config: &Config) -> String {
+    if operation.flags.iter().all(|f| *f == State::Any) {return String::new();}
+    if config.flags.is_empty() {return format!("flags={}", state_vec_to_string(&operation.flags));}
+    config.flags.iter().zip(&operation.flags)
+        .filter(|(_, state)| **state != State::Any)
+        .map(|(name, state)| format!("{}={}", name, state_vec_to_string(&[*state])))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// One row per operation sorted by counter step: the step, the flag condition and the microcodes written.
+fn rows(instruction: &Instruction, config: &Config) -> Vec<[String; 3]> {
+    let mut operations: Vec<&Operation> = instruction.operations.iter().collect();
+    operations.sort_by_key(|f| first_step(&f.counter));
+    operations.iter().map(|operation| {
+        let mut micro_operations = operation.micro_operations.clone();
+        micro_operations.sort();
+        micro_operations.dedup();
+        [format_step(&operation.counter), format_condition(operation, config), micro_operation_names(&micro_operations, config).join(" ")]
+    }).collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Reference of every instruction with its opcode pattern and the microcodes written on each counter step.
+pub fn generate_docs(instructions: &[Instruction], config: &Config, format: DocFormat) -> String {
+    let mut lines = vec![];
+    match format {
+        DocFormat::Markdown => lines.push("# Instruction set".to_owned()),
+        DocFormat::Html => {
+            lines.push("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Instruction set</title>\n</head>\n<body>".to_owned());
+            lines.push("<h1>Instruction set</h1>".to_owned());
+        }
+    }
+
+    for instruction in instructions {
+        let header = instruction.header(config);
+        let title = instruction_name(instruction, config);
+        match format {
+            DocFormat::Markdown => {
+                lines.push(String::new());
+                match &title {
+                    Some(title) => lines.push(format!("## {} `{}`", title, header)),
+                    None => lines.push(format!("## `{}`", header))
+                }
+                lines.push(String::new());
+                lines.push("| Step | Condition | Microcodes |".to_owned());
+                lines.push("|---|---|---|".to_owned());
+                for row in rows(instruction, config) {
+                    lines.push(format!("| {} | {} | {} |", row[0], row[1], row[2]));
+                }
+            },
+            DocFormat::Html => {
+                match &title {
+                    Some(title) => lines.push(format!("<h2>{} <code>{}</code></h2>", escape_html(title), escape_html(&header))),
+                    None => lines.push(format!("<h2><code>{}</code></h2>", escape_html(&header)))
+                }
+                lines.push("<table>\n<tr><th>Step</th><th>Condition</th><th>Microcodes</th></tr>".to_owned());
+                for row in rows(instruction, config) {
+                    let cells: Vec<String> = row.iter().map(|f| format!("<td>{}</td>", escape_html(f))).collect();
+                    lines.push(format!("<tr>{}</tr>", cells.join("")));
+                }
+                lines.push("</table>".to_owned());
+            }
+        }
+    }
+
+    if format == DocFormat::Html {
+        lines.push("</body>\n</html>".to_owned());
+    }
+    lines.join("\n") + "\n"
+}
@@ -1,4 +1,4 @@
-use std::{ error::Error as StdError, fmt::Display };
+use std::{ error::Error as StdError, fmt::Display, path::PathBuf };
 
 pub type Error = Box<dyn std::error::Error>;
 
@@ -9,11 +9,30 @@ pub enum ParseError {
     MissingOpcode(String),
     MissingInstruction(String),
     InstructionFormatting,
-    DataType(String),
     MissingValue(String),
     Formatting,
-    CounterOverflow,
-    CounterFormatting
+    CounterOverflow(String),
+    CounterFormatting,
+    MissingFlag(String),
+    FlagConflict(String),
+    MissingField(String),
+    FieldValue(String, String),
+    FieldConflict(String),
+    MacroFormatting,
+    MacroCallFormatting,
+    UnterminatedMacro(String),
+    DuplicateMacro(String),
+    MissingMacro(String),
+    MacroArguments(String, usize),
+    MacroRecursion(String),
+    MissingParameter(String),
+    UnknownDirective(String),
+    IncludeFormatting,
+    IncludeFile(String, String),
+    IncludeCycle(String),
+    ConstantFormatting,
+    DuplicateConstant(String),
+    MissingConstant(String)
 }
 
 impl Display for ParseError {
@@ -22,13 +41,32 @@ impl Display for ParseError {
             ParseError::OpcodeFormatting => {write!(f, "Invalid opcode formatting")},
             ParseError::OpcodeLength(opcode) => {write!(f, "Opcode '{}' has invalid length", opcode)},
             ParseError::MissingInstruction(instruction) => {write!(f, "Instruction '{}' doesn't exist", instruction)},
-            ParseError::DataType(key) => {write!(f, "Invalid data type for '{}'", key)},
             ParseError::MissingValue(value) => {write!(f, "Value '{}' doesn't exist", value)},
             ParseError::InstructionFormatting => {write!(f, "Invalid instruction formatting")},
             ParseError::Formatting => {write!(f, "Invalid formatting")},
-            ParseError::CounterOverflow => {write!(f, "Counter overflow")},
+            ParseError::CounterOverflow(instruction) => {write!(f, "Instruction {} ran out of counter space", instruction)},
             ParseError::CounterFormatting => {write!(f, "Invalid counter formatting")},
-            ParseError::MissingOpcode(opcode) => {write!(f, "Opcode '{}' doesn't exist", opcode)}
+            ParseError::MissingOpcode(opcode) => {write!(f, "Opcode '{}' doesn't exist", opcode)},
+            ParseError::MissingFlag(flag) => {write!(f, "Flag '{}' doesn't exist", flag)},
+            ParseError::FlagConflict(flag) => {write!(f, "Flag '{}' is required to be both set and clear", flag)},
+            ParseError::MissingField(field) => {write!(f, "Field '{}' doesn't exist", field)},
+            ParseError::FieldValue(field, value) => {write!(f, "Field '{}' can't be set to '{}'", field, value)},
+            ParseError::FieldConflict(field) => {write!(f, "Field '{}' is set more than once", field)},
+            ParseError::MacroFormatting => {write!(f, "Invalid macro formatting")},
+            ParseError::MacroCallFormatting => {write!(f, "Macro call has to be alone on its line")},
+            ParseError::UnterminatedMacro(name) => {write!(f, "Macro '{}' is missing '#end'", name)},
+            ParseError::DuplicateMacro(name) => {write!(f, "Macro '{}' is defined more than once", name)},
+            ParseError::MissingMacro(name) => {write!(f, "Macro '{}' doesn't exist", name)},
+            ParseError::MacroArguments(name, count) => {write!(f, "Macro '{}' takes {} arguments", name, count)},
+            ParseError::MacroRecursion(name) => {write!(f, "Macro '{}' calls itself", name)},
+            ParseError::MissingParameter(name) => {write!(f, "Parameter '{}' doesn't exist", name)},
+            ParseError::UnknownDirective(directive) => {write!(f, "Unknown directive '{}'", directive)},
+            ParseError::IncludeFormatting => {write!(f, "Invalid include formatting")},
+            ParseError::IncludeFile(path, reason) => {write!(f, "Couldn't read included file '{}': {}", path, reason)},
+            ParseError::IncludeCycle(path) => {write!(f, "Including '{}' forms a cycle", path)},
+            ParseError::ConstantFormatting => {write!(f, "Invalid constant formatting")},
+            ParseError::DuplicateConstant(name) => {write!(f, "Constant '{}' is defined more than once", name)},
+            ParseError::MissingConstant(name) => {write!(f, "Constant or opcode value '{}' doesn't exist", name)}
         }
     }
 }
@@ -39,13 +77,243 @@ impl StdError for ParseError {
             ParseError::OpcodeFormatting => "Invalid opcode formatting",
             ParseError::OpcodeLength(_) => "Invalid opcode length",
             ParseError::MissingInstruction(_) => "Instruction doesn't exist",
-            ParseError::DataType(_) => "Invalid data type",
             ParseError::MissingValue(_) => "Missing value",
             ParseError::InstructionFormatting => "Invalid instruction formatting",
             ParseError::Formatting => "Invalid formatting",
-            ParseError::CounterOverflow => "Counter overflow",
+            ParseError::CounterOverflow(_) => "Counter overflow",
             ParseError::CounterFormatting => "Invalid counter formatting",
             ParseError::MissingOpcode(_) => "Opcode doesn't exist",
+            ParseError::MissingFlag(_) => "Flag doesn't exist",
+            ParseError::FlagConflict(_) => "Conflicting flag conditions",
+            ParseError::MissingField(_) => "Field doesn't exist",
+            ParseError::FieldValue(_, _) => "Invalid field value",
+            ParseError::FieldConflict(_) => "Field set more than once",
+            ParseError::MacroFormatting => "Invalid macro formatting",
+            ParseError::MacroCallFormatting => "Invalid macro call formatting",
+            ParseError::UnterminatedMacro(_) => "Unterminated macro",
+            ParseError::DuplicateMacro(_) => "Duplicate macro",
+            ParseError::MissingMacro(_) => "Macro doesn't exist",
+            ParseError::MacroArguments(_, _) => "Invalid number of macro arguments",
+            ParseError::MacroRecursion(_) => "Recursive macro",
+            ParseError::MissingParameter(_) => "Parameter doesn't exist",
+            ParseError::UnknownDirective(_) => "Unknown directive",
+            ParseError::IncludeFormatting => "Invalid include formatting",
+            ParseError::IncludeFile(_, _) => "Couldn't read included file",
+            ParseError::IncludeCycle(_) => "Include cycle",
+            ParseError::ConstantFormatting => "Invalid constant formatting",
+            ParseError::DuplicateConstant(_) => "Duplicate constant",
+            ParseError::MissingConstant(_) => "Constant doesn't exist",
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub enum BlueprintError {
+    Prefix,
+    Encoding,
+    Checksum,
+    Header,
+    MissingLayer(u32),
+    LayerSize(u32),
+    Width(u32),
+    Row(u32)
+}
+
+impl Display for BlueprintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlueprintError::Prefix => {write!(f, "Blueprint doesn't start with 'VCB+'")},
+            BlueprintError::Encoding => {write!(f, "Blueprint isn't valid base64")},
+            BlueprintError::Checksum => {write!(f, "Blueprint checksum mismatch")},
+            BlueprintError::Header => {write!(f, "Invalid blueprint header")},
+            BlueprintError::MissingLayer(layer) => {write!(f, "Blueprint has no layer with id {}", layer)},
+            BlueprintError::LayerSize(layer) => {write!(f, "Layer {} doesn't match the blueprint dimensions", layer)},
+            BlueprintError::Width(width) => {write!(f, "Blueprint is {} cells wide, which doesn't fit a PLA for this config", width)},
+            BlueprintError::Row(row) => {write!(f, "Row {} isn't a PLA row", row)}
+        }
+    }
+}
+
+impl StdError for BlueprintError {
+    fn description(&self) -> &str {
+        match self {
+            BlueprintError::Prefix => "Invalid blueprint prefix",
+            BlueprintError::Encoding => "Invalid base64",
+            BlueprintError::Checksum => "Checksum mismatch",
+            BlueprintError::Header => "Invalid blueprint header",
+            BlueprintError::MissingLayer(_) => "Missing layer",
+            BlueprintError::LayerSize(_) => "Invalid layer size",
+            BlueprintError::Width(_) => "Invalid blueprint width",
+            BlueprintError::Row(_) => "Invalid PLA row",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    AddressWidth(u64),
+    WordWidth(u64, u64),
+    NoInputs
+}
+
+impl Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomError::AddressWidth(width) => {write!(f, "ROM image would need {} address bits", width)},
+            RomError::WordWidth(width, max) => {write!(f, "Microcode word is {} bits wide, the ROM format holds at most {}", width, max)},
+            RomError::NoInputs => {write!(f, "The config has no opcode, counter or flag bits to drive the module with")}
+        }
+    }
+}
+
+impl StdError for RomError {
+    fn description(&self) -> &str {
+        match self {
+            RomError::AddressWidth(_) => "ROM image too large",
+            RomError::WordWidth(_, _) => "ROM word too wide",
+            RomError::NoInputs => "Module without inputs",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Deserialize(String),
+    FlagCount(usize, u64),
+    DuplicateFlag(String),
+    CounterLength(u64, u64),
+    DuplicateOpcode(String),
+    ZeroLength(String),
+    OpcodeValue(ParseError),
+    DuplicateIndex(String, i64, String),
+    DuplicateField(String),
+    FieldName(String),
+    FieldValue(String, String, u64),
+    ColumnCollision(String, u64, String),
+    ColumnRange(String, u64),
+    FlagIndex(String, i64)
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Deserialize(message) => {write!(f, "{}", message)},
+            ConfigError::FlagCount(count, length) => {write!(f, "{} flags are named but 'flags_bit_length' is {}", count, length)},
+            ConfigError::DuplicateFlag(name) => {write!(f, "Flag '{}' is named more than once", name)},
+            ConfigError::CounterLength(length, max) => {write!(f, "'counter_bit_length' is {}, at most {} bits are supported", length, max)},
+            ConfigError::DuplicateOpcode(name) => {write!(f, "Opcode '{}' is defined more than once", name)},
+            ConfigError::ZeroLength(name) => {write!(f, "'{}' can't have length 0", name)},
+            ConfigError::OpcodeValue(error) => {write!(f, "{}", error)},
+            ConfigError::DuplicateIndex(name, index, other) => {write!(f, "Microcode '{}' has index {}, which '{}' already uses", name, index, other)},
+            ConfigError::DuplicateField(name) => {write!(f, "Field '{}' is defined more than once", name)},
+            ConfigError::FieldName(name) => {write!(f, "Field '{}' has the same name as a microcode", name)},
+            ConfigError::FieldValue(name, value, length) => {write!(f, "Value '{}' doesn't fit in the {} bits of field '{}'", value, length, name)},
+            ConfigError::ColumnCollision(name, column, other) => {write!(f, "Field '{}' covers column {}, which '{}' already uses", name, column, other)},
+            ConfigError::ColumnRange(name, max) => {write!(f, "'{}' doesn't fit in the {} output columns a PLA may have", name, max)},
+            ConfigError::FlagIndex(name, index) => {write!(f, "Microcode '{}' has index {}, which doesn't address a flag", name, index)}
+        }
+    }
+}
+
+impl StdError for ConfigError {
+    fn description(&self) -> &str {
+        match self {
+            ConfigError::Deserialize(_) => "Invalid config value",
+            ConfigError::FlagCount(_, _) => "Invalid number of flags",
+            ConfigError::DuplicateFlag(_) => "Duplicate flag",
+            ConfigError::CounterLength(_, _) => "Counter too wide",
+            ConfigError::DuplicateOpcode(_) => "Duplicate opcode",
+            ConfigError::ZeroLength(_) => "Zero length",
+            ConfigError::OpcodeValue(_) => "Invalid opcode value",
+            ConfigError::DuplicateIndex(_, _, _) => "Duplicate microcode index",
+            ConfigError::DuplicateField(_) => "Duplicate field",
+            ConfigError::FieldName(_) => "Field named like a microcode",
+            ConfigError::FieldValue(_, _, _) => "Field value too wide",
+            ConfigError::ColumnCollision(_, _, _) => "Column used more than once",
+            ConfigError::ColumnRange(_, _) => "Column out of range",
+            ConfigError::FlagIndex(_, _) => "Invalid flag index",
+        }
+    }
+}
+
+/// Every `ConfigError` found in a config, each with the JSON pointer to the value it was raised for.
+#[derive(Debug)]
+pub struct ConfigErrors {
+    pub path: Option<PathBuf>,
+    pub errors: Vec<(String, ConfigError)>
+}
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "Invalid config '{}'", path.display())?,
+            None => write!(f, "Invalid config")?
+        }
+        for (pointer, error) in &self.errors {
+            let pointer = if pointer.is_empty() { "top level" } else { pointer };
+            write!(f, "\n  {}: {}", pointer, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for ConfigErrors {}
+
+/// `ParseError` with the position in the source it was raised at.
+#[derive(Debug)]
+pub struct SourceError {
+    pub error: ParseError,
+    pub path: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub source_line: String
+}
+
+impl SourceError {
+    /// `offset` and `length` are in bytes, `line` and `column` are counted from 1 in characters.
+    pub fn new(error: ParseError, source: &str, offset: usize, length: usize) -> Self {
+        let line_start = source[..offset].rfind('\n').map(|f| f + 1).unwrap_or(0);
+        let line_end = source[offset..].find('\n').map(|f| f + offset).unwrap_or(source.len());
+        let source_line = source[line_start..line_end].trim_end_matches('\r').to_owned();
+        let line = source[..offset].matches('\n').count() + 1;
+        let column = source[line_start..offset].chars().count() + 1;
+        let length = source[offset..(offset + length).min(line_end)].chars().count();
+        Self { error, path: None, line, column, length, source_line }
+    }
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = self.path.as_ref().map(|f| f.display().to_string()).unwrap_or("<input>".to_owned());
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "{}", self.error)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, path, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        // tabs are kept so the carets line up with the text however wide the terminal draws them
+        let indent: String = self.source_line.chars().take(self.column - 1).map(|f| if f == '\t' { '\t' } else { ' ' }).collect();
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.length.max(1)))
+    }
+}
+
+impl StdError for SourceError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceErrors(pub Vec<SourceError>);
+
+impl Display for SourceErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.len() != 1 {
+            write!(f, "{} errors\n\n", self.0.len())?;
+        }
+        let errors = self.0.iter().map(|f| f.to_string()).collect::<Vec<String>>();
+        write!(f, "{}", errors.join("\n\n"))
+    }
+}
+
+impl StdError for SourceErrors {}
\ No newline at end of file
@@ -0,0 +1,198 @@
+use crate::{microcode::{is_directive, parse_comment, parse_constant, parse_header, parse_include, parse_line, parse_macro_header, Line, Token}, Config};
+
+/// What follows a construct on its line, empty or a comment with one space before it. `None` if anything else is left.
+fn trailing_comment(rest: &str) -> Option<String> {
+    let rest = rest.trim();
+    if rest.is_empty() {return Some(String::new());}
+    parse_comment(rest).ok().filter(|f| f.0.is_empty()).map(|_| format!(" {}", rest))
+}
+
+fn format_token(token: &Token) -> String {
+    match token {
+        Token::Condition(name, value) => format!("?{}={}", name, value),
+        Token::Assignment(name, value) => format!("{}={}", name, value),
+        Token::Call(name, arguments) if arguments.is_empty() => format!("@{}", name),
+        Token::Call(name, arguments) => format!("@{}({})", name, arguments.join(", ")),
+        Token::Word(word) => word.to_string()
+    }
+}
+
+/// Conditions come first in flag order, then microcodes and fields by output column. Names the config doesn't know keep their order at the end.
+fn token_order(token: &Token, config: &Config) -> (u8, i64) {
+    match token {
+        Token::Condition(name, _) => (0, config.flags.iter().position(|f| f == name).map_or(i64::MAX, |f| f as i64)),
+        Token::Word(word) => config.microcode_map.get(*word).map_or((2, 0), |f| (1, *f)),
+        Token::Assignment(name, _) => config.fields.iter().find(|f| f.name == *name).map_or((2, 0), |f| (1, f.index as i64)),
+        Token::Call(_, _) => (2, 0)
+    }
+}
+
+struct Formatter<'c> {
+    config: &'c Config,
+    /// Longest value written for each opcode, 0 if no header sets it.
+    value_widths: Vec<usize>,
+    counter_width: usize
+}
+
+impl Formatter<'_> {
+    /// Opcodes in config order with every value padded to the longest one in the file, so the `=` line up across headers.
+    fn header(&self, header: &[(&str, &str)]) -> String {
+        let mut parts = vec![];
+        for ((name, _), value_width) in self.config.opcodes.iter().zip(&self.value_widths) {
+            if *value_width == 0 {continue;}
+            let width = name.len() + 1 + value_width;
+            // a later value for the same opcode wins when parsing
+            match header.iter().rev().find(|f| f.0 == name) {
+                Some((_, value)) => parts.push(format!("{:width$}", format!("{}={}", name, value))),
+                None => parts.push(" ".repeat(width))
+            }
+        }
+        for (name, value) in header.iter().filter(|f| !self.config.opcodes.iter().any(|opcode| opcode.0 == f.0)) {
+            parts.push(format!("{}={}", name, value));
+        }
+        format!("[{}]", parts.join(" ").trim_end())
+    }
+
+    fn operation(&self, line: &Line) -> String {
+        let mut tokens = line.tokens.clone();
+        tokens.sort_by_key(|f| token_order(f, self.config));
+        let tokens: Vec<String> = tokens.iter().map(format_token).collect();
+        format!("{:width$} {}", line.counter, tokens.join(" "), width = self.counter_width)
+    }
+
+    fn directive(&self, line: &str) -> Option<String> {
+        if let Some(rest) = line.strip_prefix("#end") {
+            return trailing_comment(rest).map(|comment| format!("#end{}", comment));
+        }
+        if let Ok((rest, (name, parameters))) = parse_macro_header(line) {
+            let parameters = if parameters.is_empty() { String::new() } else { format!("({})", parameters.join(", ")) };
+            return trailing_comment(rest).map(|comment| format!("#macro {}{}{}", name, parameters, comment));
+        }
+        if let Ok((rest, (name, value))) = parse_constant(line) {
+            return trailing_comment(rest).map(|comment| format!("#const {} = {}{}", name, value, comment));
+        }
+        if let Ok((rest, path)) = parse_include(line) {
+            return trailing_comment(rest).map(|comment| format!("#include \"{}\"{}", path, comment));
+        }
+        None
+    }
+
+    /// The line in canonical form, a header followed by an operation is split in two. Lines that don't parse are kept as written.
+    fn line(&self, line: &str) -> Vec<String> {
+        if line.starts_with("//") {
+            return vec![line.to_owned()];
+        }
+        if line.starts_with('[') {
+            if let Ok((rest, header)) = parse_header(line) {
+                let header = self.header(&header);
+                if let Some(comment) = trailing_comment(rest) {
+                    return vec![header + &comment];
+                }
+                if let Ok((rest, operation)) = parse_line(rest.trim_start()) {
+                    if let Some(comment) = trailing_comment(rest) {
+                        return vec![header, self.operation(&operation) + &comment];
+                    }
+                }
+            }
+        } else if is_directive(line) {
+            if let Some(directive) = self.directive(line) {
+                return vec![directive];
+            }
+        } else if let Ok((rest, operation)) = parse_line(line) {
+            if let Some(comment) = trailing_comment(rest) {
+                return vec![self.operation(&operation) + &comment];
+            }
+        }
+        vec![line.to_owned()]
+    }
+}
+
+/// Rewrites a source file in one style, keeping comments: headers on one line with the opcodes aligned, counters padded to
+/// `counter_bit_length` characters, conditions before microcodes and microcodes sorted by output column. Indentation and
+/// trailing whitespace are removed and runs of blank lines become one.
+pub fn format_source(source: &str, config: &Config) -> String {
+    let mut formatter = Formatter { config, value_widths: vec![0; config.opcodes.len()], counter_width: config.counter_bit_length as usize };
+    for line in source.lines().map(str::trim) {
+        let (rest, header) = match parse_header(line) {
+            Ok((rest, header)) => (rest.trim_start(), header),
+            Err(_) => (line, vec![])
+        };
+        for (name, value) in header {
+            if let Some(index) = config.opcodes.iter().position(|f| f.0 == name) {
+                formatter.value_widths[index] = formatter.value_widths[index].max(value.len());
+            }
+        }
+        if let Ok((_, operation)) = parse_line(rest) {
+            formatter.counter_width = formatter.counter_width.max(operation.counter.len());
+        }
+    }
+
+    let mut lines: Vec<String> = vec![];
+    for line in source.lines().map(str::trim) {
+        if line.is_empty() {
+            if lines.last().is_some_and(|f| !f.is_empty()) {
+                lines.push(String::new());
+            }
+            continue;
+        }
+        lines.append(&mut formatter.line(line));
+    }
+    while lines.last().is_some_and(|f| f.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::microcode::{format_instructions, parse_instructions};
+    use super::*;
+
+    const SOURCE: &str = "// fetch and decode
+  #const   SUB=0b0010   // subtract
+#macro  LOAD( register )
+  + $register   PC_INC
+#end
+[OPCODE=ADD] 0 PC_INC ?Z=1 PC_OUT // fetch
+
+
+[OPCODE=SUB  ]
+  +   @LOAD(A_IN)
+1-2 B_IN  // operand
+";
+
+    fn config() -> Config {
+        Config::try_from(json!({
+            "opcodes": [{"name": "OPCODE", "length": 4, "values": {"ADD": "0b0001"}}],
+            "counter_bit_length": 3,
+            "flags": ["Z", "C"],
+            "microcodes": {"PC_OUT": 0, "PC_INC": 1, "A_IN": 2, "B_IN": 3}
+        })).unwrap()
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let config = config();
+        let formatted = format_source(SOURCE, &config);
+        assert_eq!(format_source(&formatted, &config), formatted);
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let formatted = format_source(SOURCE, &config());
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert_eq!(lines[0], "// fetch and decode");
+        assert!(lines.contains(&"#const SUB = 0b0010 // subtract"), "{}", formatted);
+        assert!(lines.contains(&"0   ?Z=1 PC_OUT PC_INC // fetch"), "{}", formatted);
+        assert!(lines.contains(&"1-2 B_IN // operand"), "{}", formatted);
+    }
+
+    #[test]
+    fn formatting_keeps_the_instructions() {
+        let config = config();
+        let parsed = parse_instructions(SOURCE, &config).unwrap();
+        let reparsed = parse_instructions(&format_source(SOURCE, &config), &config).unwrap();
+        assert_eq!(format_instructions(&reparsed, &config).unwrap(), format_instructions(&parsed, &config).unwrap());
+    }
+}
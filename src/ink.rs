@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RGBA {
     pub r: u8,
@@ -16,7 +17,11 @@ impl RGBA {
         RGBA {r, g, b, a: 255}
     }
 
-	pub fn to_be_bytes(&self) -> Vec<u8> {
+	pub fn darken(self) -> Self {
+		RGBA {r: self.r / 4 * 3, g: self.g / 4 * 3, b: self.b / 4 * 3, a: self.a}
+	}
+
+	pub fn to_be_bytes(self) -> Vec<u8> {
 		vec![self.r, self.g, self.b, self.a]
 	}
 }
@@ -42,6 +47,13 @@ impl InkLayer {
         }
         bytes
     }
+
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        let ink_buffer = bytes.chunks_exact(4)
+            .map(|f| RGBA::new(f[0], f[1], f[2], f[3]))
+            .collect();
+        InkLayer { ink_buffer }
+    }
 }
 
 pub struct Ink;
@@ -117,3 +129,12 @@ pub static TRACES_ORDERED: [RGBA; 16] = [
 	RGBA { r: 161, g: 85, b: 151, a: 255 }
 ];
 
+
+pub static DECORATION_BANDS: [RGBA; 6] = [
+	RGBA { r: 64, g: 44, b: 48, a: 255 },
+	RGBA { r: 44, g: 64, b: 48, a: 255 },
+	RGBA { r: 44, g: 52, b: 72, a: 255 },
+	RGBA { r: 66, g: 60, b: 40, a: 255 },
+	RGBA { r: 58, g: 44, b: 70, a: 255 },
+	RGBA { r: 40, g: 62, b: 66, a: 255 }
+];
@@ -0,0 +1,196 @@
+use crate::{
+	blueprint::{generate_layered_blueprint, Layer},
+	error::{BlueprintError, Error, ParseError},
+	ink::{Ink, InkLayer, RGBA, TRACES_ORDERED, DECORATION_BANDS},
+	microcode::{Instruction, State},
+	pla::{self, instructions_to_terms, Term},
+	Config
+};
+
+/// Appends one input row cell pair per bit, which of the two columns gets the READ depends on the gate the row feeds.
+pub fn append_state_vec_to_ink_layer(state_vec: &[State], ink_layer: &mut InkLayer, gate_ink: RGBA) {
+	for state in state_vec {
+		if gate_ink == Ink::AND {
+			match state {
+				State::True => {
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+	
+					ink_layer.ink_buffer.push(Ink::READ);
+	
+					ink_layer.ink_buffer.push(gate_ink);
+					
+				},
+				State::False => {
+					ink_layer.ink_buffer.push(Ink::READ);
+					ink_layer.ink_buffer.push(gate_ink);
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+				},
+				State::Any => {
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+				}
+			}
+		} else {
+			match state {
+				State::False => {
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+	
+					ink_layer.ink_buffer.push(Ink::READ);
+	
+					ink_layer.ink_buffer.push(gate_ink);
+					
+				},
+				State::True => {
+					ink_layer.ink_buffer.push(Ink::READ);
+					ink_layer.ink_buffer.push(gate_ink);
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+				},
+				State::Any => {
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+					ink_layer.ink_buffer.push(Ink::TC_GRAY);
+					ink_layer.ink_buffer.push(gate_ink);
+				}
+			}
+		}
+		
+	}
+}
+
+/// Lays out the instructions as a PLA, the rows are optionally minimized first.
+pub fn generate_blueprint(instructions: &[Instruction], config: &Config, decoration: bool, minimize: bool) -> Result<String, Error> {
+	let mut terms = instructions_to_terms(instructions, config)?;
+	if minimize {
+		terms = pla::minimize(terms);
+	}
+	generate_terms_blueprint(&terms, config, decoration)
+}
+
+/// Lays out one AND/NOR row pair per term, alternating the gate between terms.
+pub fn generate_terms_blueprint(terms: &[Term], config: &Config, decoration: bool) -> Result<String, Error> {
+	let mut ink_buffer: InkLayer = InkLayer::empty();
+	let mut height: u32 = 0;
+	let max_index = config.max_micro_operation_index().ok_or(ParseError::MissingValue("microcodes".to_owned()))?;
+	let opcodes_length: u64 = config.opcodes.iter().map(|f| f.1).collect::<Vec<u64>>().iter().sum();
+	let width: u32 = (opcodes_length * 4 + config.counter_bit_length * 4 + (max_index + 1) * 2 + config.flags_bit_length * 4) as u32;
+	let mut gate_ink = Ink::AND;
+	let mut decoration_buffer: InkLayer = InkLayer::empty();
+
+	let mut previous_instruction = None;
+	let mut shaded = false;
+	for term in terms {
+		// consecutive rows of one instruction alternate shades so the row pairs stay distinguishable
+		shaded = previous_instruction == Some(term.instruction) && !shaded;
+		previous_instruction = Some(term.instruction);
+		let band = DECORATION_BANDS[term.instruction % DECORATION_BANDS.len()];
+		let row_ink = if shaded { band.darken() } else { band };
+		for _ in 0..width * 2 {
+			decoration_buffer.ink_buffer.push(row_ink);
+		}
+
+		for _ in 0..width/2 {
+			ink_buffer.ink_buffer.push(Ink::CROSS);
+			ink_buffer.ink_buffer.push(gate_ink);
+		}
+		height += 1;
+
+		append_state_vec_to_ink_layer(&term.inputs, &mut ink_buffer, gate_ink);
+		height += 1;
+
+		for i in 0..=max_index {
+			if term.micro_operations.contains(&i) {
+				ink_buffer.ink_buffer.push(Ink::WRITE);
+			} else {
+				ink_buffer.ink_buffer.push(TRACES_ORDERED[i as usize % 16]);
+			}
+			ink_buffer.ink_buffer.push(gate_ink);
+		}
+		if gate_ink == Ink::AND {
+			gate_ink = Ink::NOR;
+		} else {
+			gate_ink = Ink::AND
+		}
+	}
+
+	if decoration {
+		generate_layered_blueprint(&[
+			(Layer::Logic, &ink_buffer),
+			(Layer::DecorationOn, &decoration_buffer),
+			(Layer::DecorationOff, &decoration_buffer)
+		], width, height)
+	} else {
+		generate_layered_blueprint(&[(Layer::Logic, &ink_buffer)], width, height)
+	}
+}
+
+/// Reads the terms back out of a PLA laid out by `generate_terms_blueprint` for the same config, every term gets instruction 0.
+pub fn decode_terms(ink_layer: &InkLayer, width: u32, height: u32, config: &Config) -> Result<Vec<Term>, Error> {
+	let max_index = config.max_micro_operation_index().ok_or(ParseError::MissingValue("microcodes".to_owned()))?;
+	let input_length = (config.opcodes.iter().map(|f| f.1).sum::<u64>() + config.counter_bit_length + config.flags_bit_length) as usize;
+	if width as usize != input_length * 4 + (max_index as usize + 1) * 2 {
+		return Err(BlueprintError::Width(width).into());
+	}
+	if !height.is_multiple_of(2) {
+		return Err(BlueprintError::Row(height - 1).into());
+	}
+
+	let mut terms = vec![];
+	for (pair, rows) in ink_layer.ink_buffer.chunks(width as usize * 2).enumerate() {
+		let row = pair as u32 * 2 + 1;
+		let cells = &rows[width as usize..];
+		let gate_ink = cells[1];
+		if gate_ink != Ink::AND && gate_ink != Ink::NOR {
+			return Err(BlueprintError::Row(row).into());
+		}
+
+		let mut inputs = vec![];
+		for bit in cells[..input_length * 4].chunks(4) {
+			// the READ sits in the first column of a bit for AND rows matching 0 and NOR rows matching 1
+			let state = match (bit[0] == Ink::READ, bit[2] == Ink::READ) {
+				(false, false) => State::Any,
+				(true, false) if gate_ink == Ink::AND => State::False,
+				(true, false) => State::True,
+				(false, true) if gate_ink == Ink::AND => State::True,
+				(false, true) => State::False,
+				(true, true) => return Err(BlueprintError::Row(row).into())
+			};
+			inputs.push(state);
+		}
+		let micro_operations = (0..=max_index).filter(|i| cells[input_length * 4 + *i as usize * 2] == Ink::WRITE).collect();
+		terms.push(Term { inputs, micro_operations, instruction: 0 });
+	}
+	Ok(terms)
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+	use crate::{blueprint::decode_logic_blueprint, microcode::{format_instructions, parse_instructions}, pla::{minimize, terms_to_instructions}, rom::rom_words};
+	use super::*;
+
+	#[test]
+	fn decoded_source_builds_the_same_rom() {
+		let config = Config::try_from(json!({
+			"opcodes": [{"name": "OPCODE", "length": 4}],
+			"counter_bit_length": 3,
+			"flags": ["Z", "C"],
+			"microcodes": {"PC_OUT": 0, "PC_INC": 1, "A_LOAD": 2, "RESET": 3}
+		})).unwrap();
+		let source = "[OPCODE=0011]\n0 A_LOAD\n4 A_LOAD\n##1 PC_INC\n[OPCODE=01##]\n+ PC_OUT\n+ ?Z=1 A_LOAD\n+ ?Z=0 RESET\n";
+		let terms = instructions_to_terms(&parse_instructions(source, &config).unwrap(), &config).unwrap();
+		for terms in [terms.clone(), minimize(terms)] {
+			let blueprint = generate_terms_blueprint(&terms, &config, false).unwrap();
+			let (ink_layer, width, height) = decode_logic_blueprint(&blueprint).unwrap();
+			let decoded = decode_terms(&ink_layer, width, height, &config).unwrap();
+			let decoded_source = format_instructions(&terms_to_instructions(&decoded, &config), &config).unwrap();
+			let rebuilt = instructions_to_terms(&parse_instructions(&decoded_source, &config).unwrap(), &config).unwrap();
+			assert_eq!(rom_words(&terms, &config).unwrap(), rom_words(&rebuilt, &config).unwrap(), "{}", decoded_source);
+		}
+	}
+}
@@ -0,0 +1,21 @@
+pub mod ink;
+pub mod blueprint;
+pub mod microcode;
+pub mod error;
+pub mod pla;
+pub mod analysis;
+pub mod simulate;
+pub mod rom;
+pub mod verilog;
+pub mod docs;
+pub mod format;
+pub mod lsp;
+mod config;
+mod layout;
+
+pub use config::{Config, Field, DEFAULT_CONFIG};
+pub use microcode::{parse_instructions, parse_source, parse_file, included_files, source_included_files, format_instructions, Instruction, Operation, State};
+pub use ink::InkLayer;
+pub use blueprint::{generate_logic_blueprint, generate_layered_blueprint, decode_logic_blueprint, decode_blueprint_layer, Layer};
+pub use layout::{generate_blueprint, generate_terms_blueprint, decode_terms, append_state_vec_to_ink_layer};
+pub use error::{Error, ParseError, SourceError, SourceErrors, BlueprintError, RomError, ConfigError, ConfigErrors};
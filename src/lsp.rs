@@ -0,0 +1,383 @@
+use std::{collections::HashMap, fs, io::{BufRead, Write}, path::{Path, PathBuf}};
+use serde_json::{json, Value};
+use crate::{error::{Error, SourceErrors}, microcode::{source_included_files, parse_include, parse_macro_header, parse_source}, Config};
+
+/// Message that couldn't be read, with the id of the request if it could be recovered.
+struct Malformed {
+    reason: String,
+    id: Option<Value>
+}
+
+/// Id of a request whose body isn't valid JSON, if it's written as `"id": <number or string>`.
+fn recover_id(body: &[u8]) -> Option<Value> {
+    let body = String::from_utf8_lossy(body);
+    let rest = body[body.find("\"id\"")? + 4..].trim_start().strip_prefix(':')?;
+    serde_json::Deserializer::from_str(rest).into_iter::<Value>().next()?.ok().filter(|f| f.is_number() || f.is_string())
+}
+
+/// Reads one JSON-RPC message framed by a `Content-Length` header, `None` at the end of the input.
+/// Only I/O errors are returned as errors, a malformed frame or body is skipped so the session can go on.
+fn read_message(input: &mut impl BufRead) -> Result<Option<Result<Value, Malformed>>, Error> {
+    let mut length = Err("Message without Content-Length".to_owned());
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {return Ok(None);}
+        let line = line.trim_end();
+        if line.is_empty() {break;}
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().map_err(|_| format!("Invalid Content-Length '{}'", value.trim()));
+        }
+    }
+    let length = match length {
+        Ok(length) => length,
+        Err(reason) => return Ok(Some(Err(Malformed { reason, id: None })))
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body).map_err(|e| Malformed { reason: format!("Invalid message: {}", e), id: recover_id(&body) })))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<(), Error> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = path.get(i + 1..i + 3).filter(|_| path.as_bytes()[i] == b'%').and_then(|f| u8::from_str_radix(f, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            },
+            None => {
+                bytes.push(path.as_bytes()[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or(path.to_owned());
+    format!("file://{}", path.display().to_string().replace('%', "%25").replace(' ', "%20"))
+}
+
+fn position(line: usize, character: usize) -> Value {
+    json!({"line": line, "character": character})
+}
+
+fn range(line: usize, start: usize, end: usize) -> Value {
+    json!({"start": position(line, start), "end": position(line, end)})
+}
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || "_-!".contains(ch)
+}
+
+/// Word at a position and the character before it, `@` for macro calls and `$` for parameters.
+fn word_at(text: &str, line: usize, character: usize) -> Option<(String, Option<char>)> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    let character = character.min(chars.len());
+    let start = (0..character).rev().take_while(|f| is_word_char(chars[*f])).last().unwrap_or(character);
+    let end = (character..chars.len()).take_while(|f| is_word_char(chars[*f])).last().map_or(character, |f| f + 1);
+    if start == end {return None;}
+    let prefix = start.checked_sub(1).map(|f| chars[f]);
+    Some((chars[start..end].iter().collect(), prefix))
+}
+
+/// Line and character range of the name in a `#macro NAME` line.
+fn find_macro(text: &str, name: &str) -> Option<(usize, usize)> {
+    text.lines().enumerate().find_map(|(index, line)| {
+        let trimmed = line.trim_start();
+        let (_, (found, _)) = parse_macro_header(trimmed).ok()?;
+        let column = line[..line.len() - trimmed.len()].chars().count() + trimmed[..found.as_ptr() as usize - trimmed.as_ptr() as usize].chars().count();
+        (found == name).then_some((index, column))
+    })
+}
+
+struct Server<W: Write, F: Fn(&Path) -> Result<Config, Error>> {
+    output: W,
+    load_config: F,
+    /// Text of every open document by URI.
+    documents: HashMap<String, String>,
+    /// URIs diagnostics were published for when checking each document, they're cleared on the next check.
+    published: HashMap<String, Vec<String>>
+}
+
+impl<W: Write, F: Fn(&Path) -> Result<Config, Error>> Server<W, F> {
+    fn send(&mut self, message: Value) -> Result<(), Error> {
+        write_message(&mut self.output, &message)
+    }
+
+    /// Text of a file, from the editor if it's open.
+    fn text(&self, path: &Path) -> Option<String> {
+        self.documents.get(&path_to_uri(path)).cloned().or_else(|| fs::read_to_string(path).ok())
+    }
+
+    /// Parses the document and publishes the errors for it and every included file they're in.
+    fn check(&mut self, uri: &str) -> Result<(), Error> {
+        let Some(text) = self.documents.get(uri) else {return Ok(());};
+        let path = uri_to_path(uri);
+        let mut diagnostics: HashMap<String, Vec<Value>> = HashMap::from([(uri.to_owned(), vec![])]);
+        match (self.load_config)(&path) {
+            Ok(config) => {
+                if let Err(SourceErrors(errors)) = parse_source(text, Some(&path), &config) {
+                    for error in errors {
+                        let file = error.path.as_ref().map_or(uri.to_owned(), |f| path_to_uri(f));
+                        let file = if file == path_to_uri(&path) { uri.to_owned() } else { file };
+                        diagnostics.entry(file).or_default().push(json!({
+                            "range": range(error.line - 1, error.column - 1, error.column - 1 + error.length.max(1)),
+                            "severity": 1,
+                            "source": "vcb_mips_tools",
+                            "message": error.error.to_string()
+                        }));
+                    }
+                }
+            },
+            Err(e) => diagnostics.entry(uri.to_owned()).or_default().push(json!({
+                "range": range(0, 0, 0),
+                "severity": 1,
+                "source": "vcb_mips_tools",
+                "message": format!("Couldn't load the config: {}", e)
+            }))
+        }
+
+        let previous = self.published.remove(uri).unwrap_or_default();
+        for file in previous.iter().filter(|f| !diagnostics.contains_key(*f)) {
+            self.send(json!({"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {"uri": file, "diagnostics": []}}))?;
+        }
+        self.published.insert(uri.to_owned(), diagnostics.keys().cloned().collect());
+        for (file, diagnostics) in diagnostics {
+            self.send(json!({"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {"uri": file, "diagnostics": diagnostics}}))?;
+        }
+        Ok(())
+    }
+
+    /// Opcode names inside a header, microcode names everywhere else.
+    fn completion(&self, uri: &str, line: usize, character: usize) -> Value {
+        let Ok(config) = (self.load_config)(&uri_to_path(uri)) else {return json!([]);};
+        let text = self.documents.get(uri).map_or("", |f| f.as_str());
+        let before: String = text.lines().nth(line).unwrap_or("").chars().take(character).collect();
+        let in_header = before.rfind('[').is_some_and(|open| !before[open..].contains(']'));
+
+        let mut items = vec![];
+        if in_header {
+            for (name, length) in &config.opcodes {
+                items.push(json!({"label": name, "kind": 5, "detail": format!("opcode, {} bits", length)}));
+            }
+        } else {
+            let mut microcodes: Vec<(&String, &i64)> = config.microcode_map.iter().collect();
+            microcodes.sort_by_key(|f| (*f.1, f.0.clone()));
+            for (name, index) in microcodes {
+                items.push(json!({"label": name, "kind": 12, "detail": format!("column {}", index)}));
+            }
+        }
+        Value::Array(items)
+    }
+
+    fn hover(&self, uri: &str, line: usize, character: usize) -> Value {
+        let Ok(config) = (self.load_config)(&uri_to_path(uri)) else {return Value::Null;};
+        let Some((word, _)) = self.documents.get(uri).and_then(|f| word_at(f, line, character)) else {return Value::Null;};
+        let description = if let Some(index) = config.microcode_map.get(&word) {
+            if *index >= 0 { format!("microcode, column {}", index) } else { format!("flag condition, index {}", index) }
+        } else if let Some(field) = config.fields.iter().find(|f| f.name == word) {
+            format!("field, columns {}-{}", field.index, field.index + field.length.max(1) - 1)
+        } else if let Some((_, length)) = config.opcodes.iter().find(|f| f.0 == word) {
+            format!("opcode, {} bits", length)
+        } else if let Some(index) = config.flags.iter().position(|f| *f == word) {
+            format!("flag {}", index)
+        } else {
+            return Value::Null;
+        };
+        json!({"contents": {"kind": "markdown", "value": format!("`{}`: {}", word, description)}})
+    }
+
+    /// The file of an `#include` line, or the `#macro` line of a called macro in the document or a file it includes.
+    fn definition(&self, uri: &str, line: usize, character: usize) -> Value {
+        let Some(text) = self.documents.get(uri) else {return Value::Null;};
+        let path = uri_to_path(uri);
+        let directory = path.parent().map(Path::to_owned).unwrap_or_default();
+
+        let current = text.lines().nth(line).unwrap_or("").trim_start();
+        if let Ok((_, included)) = parse_include(current) {
+            return json!({"uri": path_to_uri(&directory.join(included)), "range": range(0, 0, 0)});
+        }
+
+        let Some((name, Some('@'))) = word_at(text, line, character) else {return Value::Null;};
+        if let Some((line, column)) = find_macro(text, &name) {
+            return json!({"uri": uri, "range": range(line, column, column + name.chars().count())});
+        }
+        for file in source_included_files(text, &path) {
+            let Some(text) = self.text(&file) else {continue;};
+            if let Some((line, column)) = find_macro(&text, &name) {
+                return json!({"uri": path_to_uri(&file), "range": range(line, column, column + name.chars().count())});
+            }
+        }
+        Value::Null
+    }
+
+    /// Logs a message that couldn't be read in the client and answers it with a parse error if it was a request.
+    fn malformed(&mut self, malformed: Malformed) -> Result<(), Error> {
+        self.send(json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"type": 1, "message": malformed.reason}}))?;
+        if let Some(id) = malformed.id {
+            self.send(json!({"jsonrpc": "2.0", "id": id, "error": {"code": -32700, "message": malformed.reason}}))?;
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, id: Value, result: Value) -> Result<(), Error> {
+        self.send(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+    }
+
+    /// Handles one message, returns `false` once the client asks the server to exit.
+    fn handle(&mut self, message: Value) -> Result<bool, Error> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_owned();
+        let (line, character) = (params["position"]["line"].as_u64().unwrap_or(0) as usize, params["position"]["character"].as_u64().unwrap_or(0) as usize);
+        let id = message.get("id").cloned();
+
+        match method {
+            "initialize" => self.respond(id.unwrap_or(Value::Null), json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "completionProvider": {"triggerCharacters": ["[", " "]},
+                    "hoverProvider": true,
+                    "definitionProvider": true
+                },
+                "serverInfo": {"name": "vcb_mips_tools"}
+            }))?,
+            "shutdown" => self.respond(id.unwrap_or(Value::Null), Value::Null)?,
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_owned();
+                self.documents.insert(uri.clone(), text);
+                self.check(&uri)?;
+            },
+            "textDocument/didChange" => {
+                // full document sync, the last change holds the whole text
+                if let Some(text) = params["contentChanges"].as_array().and_then(|f| f.last()).and_then(|f| f["text"].as_str()) {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                }
+                self.check(&uri)?;
+            },
+            "textDocument/didSave" => self.check(&uri)?,
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                for file in self.published.remove(&uri).unwrap_or_default() {
+                    self.send(json!({"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {"uri": file, "diagnostics": []}}))?;
+                }
+            },
+            "textDocument/completion" => {
+                let result = self.completion(&uri, line, character);
+                self.respond(id.unwrap_or(Value::Null), result)?;
+            },
+            "textDocument/hover" => {
+                let result = self.hover(&uri, line, character);
+                self.respond(id.unwrap_or(Value::Null), result)?;
+            },
+            "textDocument/definition" => {
+                let result = self.definition(&uri, line, character);
+                self.respond(id.unwrap_or(Value::Null), result)?;
+            },
+            _ => if let Some(id) = id {
+                self.send(json!({"jsonrpc": "2.0", "id": id, "error": {"code": -32601, "message": format!("Method '{}' not found", method)}}))?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Language server speaking JSON-RPC over `input` and `output` until the client sends `exit`.
+/// `load_config` gives the config for a document path, diagnostics use full document sync and positions count characters.
+pub fn serve(mut input: impl BufRead, output: impl Write, load_config: impl Fn(&Path) -> Result<Config, Error>) -> Result<(), Error> {
+    let mut server = Server { output, load_config, documents: HashMap::new(), published: HashMap::new() };
+    while let Some(message) = read_message(&mut input)? {
+        match message {
+            Ok(message) => if !server.handle(message)? {break;},
+            Err(malformed) => server.malformed(malformed)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    fn frame(message: Value) -> String {
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> String {
+        frame(json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))
+    }
+
+    fn notification(method: &str, params: Value) -> String {
+        frame(json!({"jsonrpc": "2.0", "method": method, "params": params}))
+    }
+
+    #[test]
+    fn scripted_session() {
+        let directory = std::env::temp_dir().join(format!("vcb_mips_tools_lsp_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let directory = fs::canonicalize(directory).unwrap();
+        fs::write(directory.join("fetch.mc"), "#macro FETCH\n+ PC_OUT MAR_IN\n+ RAM_OUT IR_IN PC_INC\n#end\n").unwrap();
+        let uri = path_to_uri(&directory.join("main.mc"));
+        let fetch_uri = path_to_uri(&directory.join("fetch.mc"));
+        let text = "#include \"fetch.mc\"\n[OPCODE=0001]\n0 @FETCH\n2 ALU_OUT BOGUS\n";
+        let document = json!({"uri": uri});
+
+        let input = [
+            request(1, "initialize", json!({})),
+            notification("initialized", json!({})),
+            notification("textDocument/didOpen", json!({"textDocument": {"uri": uri, "text": text}})),
+            request(2, "textDocument/hover", json!({"textDocument": document, "position": {"line": 3, "character": 4}})),
+            request(3, "textDocument/completion", json!({"textDocument": document, "position": {"line": 1, "character": 1}})),
+            request(4, "textDocument/completion", json!({"textDocument": document, "position": {"line": 3, "character": 0}})),
+            request(5, "textDocument/definition", json!({"textDocument": document, "position": {"line": 2, "character": 4}})),
+            request(6, "textDocument/definition", json!({"textDocument": document, "position": {"line": 0, "character": 3}})),
+            request(7, "shutdown", Value::Null),
+            notification("exit", Value::Null)
+        ].concat();
+        let mut output = vec![];
+        let load_config = |_: &Path| Config::try_from(json!({
+            "opcodes": [{"name": "OPCODE", "length": 4}, {"name": "FUNCT", "length": 2}],
+            "counter_bit_length": 3,
+            "flags": ["Z", "C"],
+            "microcodes": {"PC_OUT": 0, "MAR_IN": 1, "RAM_OUT": 2, "IR_IN": 3, "PC_INC": 4, "ALU_OUT": 5, "A_IN": 6}
+        }));
+        serve(Cursor::new(input), &mut output, load_config).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let mut messages = vec![];
+        let mut output = output.as_slice();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message.ok().unwrap());
+        }
+        let result = |id: u64| messages.iter().find(|f| f["id"] == id).map(|f| f["result"].clone()).unwrap();
+
+        assert_eq!(result(1)["capabilities"]["hoverProvider"], true);
+        let diagnostics = messages.iter().find(|f| f["method"] == "textDocument/publishDiagnostics" && f["params"]["uri"] == uri).unwrap();
+        let diagnostics = diagnostics["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["message"], "Instruction 'BOGUS' doesn't exist");
+        assert_eq!(diagnostics[0]["range"], range(3, 10, 15));
+        assert_eq!(result(2)["contents"]["value"], "`ALU_OUT`: microcode, column 5");
+        let labels = |id: u64| result(id).as_array().unwrap().iter().map(|f| f["label"].as_str().unwrap().to_owned()).collect::<Vec<String>>();
+        assert_eq!(labels(3), ["OPCODE", "FUNCT"]);
+        assert_eq!(labels(4), ["PC_OUT", "MAR_IN", "RAM_OUT", "IR_IN", "PC_INC", "ALU_OUT", "A_IN"]);
+        assert_eq!(result(5), json!({"uri": fetch_uri, "range": range(0, 7, 12)}));
+        assert_eq!(result(6), json!({"uri": fetch_uri, "range": range(0, 0, 0)}));
+        assert_eq!(result(7), Value::Null);
+    }
+}
@@ -1,214 +1,349 @@
-mod ink;
-mod blueprint;
-mod microcode;
-mod error;
+use std::io::{self, Write};
+use std::fs::{self, File};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
 
-use ink::{Ink, InkLayer, RGBA, TRACES_ORDERED};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use vcb_mips_tools::{parse_file, included_files, format_instructions, generate_terms_blueprint, decode_logic_blueprint, decode_terms, Config, Instruction, SourceErrors, DEFAULT_CONFIG, Error};
+use vcb_mips_tools::pla::{self, instructions_to_terms, terms_to_instructions};
+use vcb_mips_tools::analysis::{find_overlaps, find_uncovered, format_inputs, micro_operation_names};
+use vcb_mips_tools::simulate::{simulate, parse_opcode_values, parse_flag_values};
+use vcb_mips_tools::rom::{rom_words, generate_rom, RomFormat};
+use vcb_mips_tools::verilog::{generate_verilog, generate_testbench};
+use vcb_mips_tools::docs::{generate_docs, DocFormat};
+use vcb_mips_tools::format::format_source;
+use vcb_mips_tools::lsp;
 
-use std::io::{Write, Read};
-use std::fs::File;
-use blueprint::generate_logic_blueprint;
-
-use clap::Parser;
-use microcode::{parse_instructions, State, str_to_state_vec};
-use serde_json::{from_reader, Value};
-use std::collections::HashMap;
-use error::ParseError;
-use std::path::Path;
-use error::Error;
+/// Exit codes, scripts can tell from them why a run failed. Invalid arguments exit with clap's code 2.
+const EXIT_SOURCE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_CONFIG: u8 = 3;
+const EXIT_IO: u8 = 4;
+const EXIT_WARNINGS: u8 = 5;
+const EXIT_BLUEPRINT: u8 = 6;
+const EXIT_EXPORT: u8 = 7;
+const EXIT_UNFORMATTED: u8 = 8;
 
 #[derive(Parser)]
 struct Cli {
-	input: std::path::PathBuf
-}
-
-pub struct Config {
-	opcodes: Vec<(String, u64)>,
-	microcode_map: HashMap<String, i64>,
-    counter_bit_length: u64,
-	flags_bit_length: u64
-}
-
-impl TryFrom<Value> for Config {
-	type Error = Error;
-	fn try_from(value: Value) -> Result<Self, Self::Error> {
-		let opcodes_serde = value.get("opcodes")
-			.ok_or(ParseError::MissingValue("opcodes".to_owned()))?
-			.as_array()
-			.ok_or(ParseError::DataType("opcodes".to_owned()))?;
-
-		let counter_bit_length = value.get("counter_bit_length")
-			.ok_or(ParseError::MissingValue("counter_bit_length".to_owned()))?
-			.as_u64()
-			.ok_or(ParseError::DataType("counter_bit_length".to_owned()))?;
-
-		let microcode_serde_map = value.get("microcodes")
-			.ok_or(ParseError::MissingValue("microcodes".to_owned()))?
-			.as_object()
-			.ok_or(ParseError::MissingValue("microcodes".to_owned()))?;
-
-		let flags_bit_length = value.get("flags_bit_length")
-		.ok_or(ParseError::MissingValue("flags_bit_length".to_owned()))?
-		.as_u64()
-		.ok_or(ParseError::DataType("flags_bit_length".to_owned()))?;
-
-		let mut microcode_map: HashMap<String, i64> = HashMap::new();
-		for (key, value) in microcode_serde_map {
-			let value = value.as_i64().ok_or(ParseError::DataType(key.clone()))?;
-			microcode_map.insert(key.clone(), value);
-		}
-
-		let mut opcodes = vec![];
-		for opcode in opcodes_serde {
-			let opcode = opcode.as_object().ok_or(ParseError::DataType("opcode".to_owned()))?;
-			let name = opcode.get("name").ok_or(ParseError::MissingValue("name".to_owned()))?.as_str().ok_or(ParseError::DataType("name".to_owned()))?.to_owned();
-			let value = opcode.get("length").ok_or(ParseError::MissingValue("length".to_owned()))?.as_u64().ok_or(ParseError::DataType("length".to_owned()))?;
-			opcodes.push((name, value));
-		}
-		Ok(Self { opcodes, microcode_map, counter_bit_length, flags_bit_length })
-	}
+	#[command(subcommand)]
+	command: Command,
+	/// Config file, by default config.json next to the input file or in the working directory
+	#[arg(long, global = true)]
+	config: Option<PathBuf>
 }
 
-fn main() -> Result<(), Error> {
-	let default_config = include_bytes!("default_config.json");
-	if !Path::new("config.json").exists() {
-		File::create("config.json").unwrap().write_all(default_config).unwrap();
+#[derive(Subcommand)]
+enum Command {
+	/// Lay out the microcode as a blueprint or export it in another format
+	Build(BuildArgs),
+	/// Parse and analyse the microcode without writing any output
+	Check {
+		input: PathBuf,
+		/// Report opcode and counter combinations no instruction handles
+		#[arg(long)]
+		coverage: bool,
+		/// Fail if there are overlaps or, with --coverage, unhandled patterns
+		#[arg(long)]
+		strict: bool
+	},
+	/// Turn a blueprint laid out by `build` back into microcode source
+	Decode {
+		/// File holding the blueprint string
+		input: PathBuf,
+		/// Output file, standard output if not given
+		#[arg(short, long)]
+		output: Option<PathBuf>
+	},
+	/// Rewrite source files in the canonical style
+	Fmt {
+		#[arg(required = true)]
+		inputs: Vec<PathBuf>,
+		/// Only report files that aren't formatted, without changing them
+		#[arg(long)]
+		check: bool
+	},
+	/// Print the microcodes written on every counter tick for one opcode
+	Simulate {
+		input: PathBuf,
+		/// Opcode value as NAME=VALUE, opcodes that aren't given are all zeros
+		#[arg(long = "opcode", value_name = "NAME=VALUE")]
+		opcodes: Vec<String>,
+		/// Flag bits or NAME=BIT pairs separated by commas, all zeros if not given
+		#[arg(long)]
+		flags: Option<String>
+	},
+	/// Run a language server on standard input and output, for diagnostics, completion, hover and go-to-definition in editors
+	Lsp,
+	/// Print the JSON Schema of the config file
+	Schema {
+		/// Output file, standard output if not given
+		#[arg(short, long)]
+		output: Option<PathBuf>
+	},
+	/// Write the config template
+	Init {
+		#[arg(default_value = "config.json")]
+		path: PathBuf,
+		/// Overwrite the file if it exists
+		#[arg(long)]
+		force: bool
 	}
-	let args = Cli::parse();
-
-	let mut input_file = File::open(args.input)?;
-	let mut input = String::new();
-	input_file.read_to_string(&mut input)?;
+}
 
-	println!("{}", generate_blueprint(&input)?);
-	Ok(())
+#[derive(Args)]
+struct BuildArgs {
+	input: PathBuf,
+	/// Output file, standard output if not given
+	#[arg(short, long)]
+	output: Option<PathBuf>,
+	#[arg(long, value_enum, default_value_t = Format::Blueprint)]
+	format: Format,
+	/// Paint a band behind every instruction on the decoration layers
+	#[arg(long)]
+	decoration: bool,
+	/// Merge product terms before laying out the PLA
+	#[arg(long)]
+	minimize: bool,
+	/// Report opcode and counter combinations no instruction handles
+	#[arg(long)]
+	coverage: bool,
+	/// Name of the Verilog module
+	#[arg(long, default_value = "microcode")]
+	module: String,
+	/// Build again whenever the input, a file it includes or the config changes
+	#[arg(long)]
+	watch: bool
 }
 
-fn append_state_vec_to_ink_layer(state_vec: &Vec<State>, ink_layer: &mut InkLayer, gate_ink: RGBA) {
-	for state in state_vec {
-		if gate_ink == Ink::AND {
-			match state {
-				State::True => {
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-	
-					ink_layer.ink_buffer.push(Ink::READ);
-	
-					ink_layer.ink_buffer.push(gate_ink);
-					
-				},
-				State::False => {
-					ink_layer.ink_buffer.push(Ink::READ);
-					ink_layer.ink_buffer.push(gate_ink);
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-				},
-				State::Any => {
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-				}
-			}
-		} else {
-			match state {
-				State::False => {
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-	
-					ink_layer.ink_buffer.push(Ink::READ);
-	
-					ink_layer.ink_buffer.push(gate_ink);
-					
-				},
-				State::True => {
-					ink_layer.ink_buffer.push(Ink::READ);
-					ink_layer.ink_buffer.push(gate_ink);
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-				},
-				State::Any => {
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-					ink_layer.ink_buffer.push(Ink::TC_GRAY);
-					ink_layer.ink_buffer.push(gate_ink);
-				}
-			}
-		}
-		
-	}
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+	/// VCB blueprint string
+	Blueprint,
+	/// Raw ROM image
+	Binary,
+	/// ROM image as Intel HEX
+	Ihex,
+	/// ROM image for Logisim
+	Logisim,
+	/// ROM image of 32-bit words for VCB's VMEM
+	Vmem,
+	/// Verilog module
+	Verilog,
+	/// Verilog testbench for the module, the expected words are written to a `.hex` file beside it
+	Testbench,
+	/// Instruction set reference in Markdown
+	Markdown,
+	/// Instruction set reference in HTML
+	Html
 }
 
-fn generate_blueprint(input: &String) -> Result<String, Error> {
-	let config_file = File::open("config.json")?;
-	let config_serde: Value = from_reader(config_file)?;
-	let config = Config::try_from(config_serde)?;
+/// Error with the exit code the run ends with.
+struct Failure {
+	code: u8,
+	error: Error
+}
 
-	let instructions = parse_instructions(&input, &config)?;
+trait ExitWith<T> {
+	fn exit_with(self, code: u8) -> Result<T, Failure>;
+}
 
-	let mut ink_buffer: InkLayer = InkLayer::empty();
-	let mut height: u32 = 0;
-	let max_index = config.microcode_map.values().max().ok_or(ParseError::MissingValue("microcodes".to_owned()))?.clone() as u64;
-	let opcodes_length: u64 = config.opcodes.iter().map(|f| f.1).collect::<Vec<u64>>().iter().sum();
-	let width: u32 = (opcodes_length * 4 + config.counter_bit_length * 4 + (max_index + 1) * 2 + config.flags_bit_length * 4) as u32;
-	let mut gate_ink = Ink::AND;
+impl<T, E: Into<Error>> ExitWith<T> for Result<T, E> {
+	fn exit_with(self, code: u8) -> Result<T, Failure> {
+		self.map_err(|e| Failure { code, error: e.into() })
+	}
+}
 
-	for instruction in instructions {
-		for operation in &instruction.operations {
-			for _ in 0..width/2 {
-				ink_buffer.ink_buffer.push(Ink::CROSS);
-				ink_buffer.ink_buffer.push(gate_ink);
-			}
-			height += 1;
+fn main() -> ExitCode {
+	match run(Cli::parse()) {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(failure) => {
+			eprintln!("Error: {}", failure.error);
+			ExitCode::from(failure.code)
+		}
+	}
+}
 
-			for opcode in &instruction.opcodes {
-				append_state_vec_to_ink_layer(opcode, &mut ink_buffer, gate_ink);
+fn run(args: Cli) -> Result<(), Failure> {
+	match &args.command {
+		Command::Init { path, force } => {
+			if path.exists() && !force {
+				return Err(format!("'{}' already exists, use --force to overwrite it", path.display())).exit_with(EXIT_IO);
 			}
-			let counter_string = format!("{:0>width$b}", operation.counter, width = config.counter_bit_length.clone() as usize);
-			let counter_state_vec = str_to_state_vec(&counter_string)?;
-
-			append_state_vec_to_ink_layer(&counter_state_vec, &mut ink_buffer, gate_ink);
-			for i in (1..=config.flags_bit_length * 2).rev() {
-				if gate_ink == Ink::AND {
-					if operation.micro_operations.contains(&-(i as i64)) {
-						ink_buffer.ink_buffer.push(Ink::READ);
-					} else {
-						ink_buffer.ink_buffer.push(Ink::TC_GRAY);
-					}
-					ink_buffer.ink_buffer.push(gate_ink);
+			File::create(path).and_then(|mut f| f.write_all(DEFAULT_CONFIG.as_bytes())).exit_with(EXIT_IO)?;
+			eprintln!("Wrote config template to '{}'", path.display());
+		},
+		Command::Simulate { input, opcodes, flags } => {
+			let config = load_config(args.config.as_deref(), input)?;
+			let instructions = parse(input, &config)?;
+			let opcode = parse_opcode_values(opcodes, &config).exit_with(EXIT_USAGE)?;
+			let flags = parse_flag_values(flags.as_deref(), &config).exit_with(EXIT_USAGE)?;
+			for (counter, micro_operations) in simulate(&instructions, &config, &opcode, &flags).exit_with(EXIT_SOURCE)?.iter().enumerate() {
+				if micro_operations.is_empty() {
+					println!("{:>3}: -", counter);
 				} else {
-					let mut j = i;
-					if i % 2 == 0 {
-						j -= 1;
-					} else {
-						j += 1;
-					}
-					if operation.micro_operations.contains(&-(j as i64)) {
-						ink_buffer.ink_buffer.push(Ink::READ);
-					} else {
-						ink_buffer.ink_buffer.push(Ink::TC_GRAY);
-					}
-					ink_buffer.ink_buffer.push(gate_ink);
+					println!("{:>3}: {}", counter, micro_operation_names(micro_operations, &config).join(" "));
 				}
-				
 			}
-			height += 1;
-
-			for i in 0..=max_index {
-				if operation.micro_operations.contains(&(i as i64)) {
-					ink_buffer.ink_buffer.push(Ink::WRITE);
+		},
+		Command::Check { input, coverage, strict } => {
+			let config = load_config(args.config.as_deref(), input)?;
+			let instructions = parse(input, &config)?;
+			let warnings = analyse(&instructions, &config, *coverage)?;
+			if *strict && warnings > 0 {
+				return Err(format!("{} warnings", warnings)).exit_with(EXIT_WARNINGS);
+			}
+		},
+		Command::Decode { input, output } => {
+			let config = load_config(args.config.as_deref(), input)?;
+			let blueprint = fs::read_to_string(input).map_err(|e| format!("Couldn't read '{}': {}", input.display(), e)).exit_with(EXIT_IO)?;
+			let (ink_layer, width, height) = decode_logic_blueprint(blueprint.trim()).exit_with(EXIT_BLUEPRINT)?;
+			let terms = decode_terms(&ink_layer, width, height, &config).exit_with(EXIT_BLUEPRINT)?;
+			let source = format_instructions(&terms_to_instructions(&terms, &config), &config).exit_with(EXIT_CONFIG)?;
+			write_output(output.as_deref(), source.as_bytes())?;
+		},
+		Command::Fmt { inputs, check } => {
+			let mut unformatted = 0;
+			for input in inputs {
+				let config = load_config(args.config.as_deref(), input)?;
+				let source = fs::read_to_string(input).map_err(|e| format!("Couldn't read '{}': {}", input.display(), e)).exit_with(EXIT_IO)?;
+				let formatted = format_source(&source, &config);
+				if formatted == source {continue;}
+				if *check {
+					eprintln!("'{}' isn't formatted", input.display());
+					unformatted += 1;
 				} else {
-					ink_buffer.ink_buffer.push(TRACES_ORDERED[i as usize % 16]);
+					write_output(Some(input), formatted.as_bytes())?;
 				}
-				ink_buffer.ink_buffer.push(gate_ink);
 			}
-			if gate_ink == Ink::AND {
-				gate_ink = Ink::NOR;
-			} else {
-				gate_ink = Ink::AND
+			if unformatted > 0 {
+				return Err(format!("{} files aren't formatted", unformatted)).exit_with(EXIT_UNFORMATTED);
 			}
+		},
+		Command::Schema { output } => {
+			let schema = serde_json::to_string_pretty(&Config::schema()).exit_with(EXIT_IO)? + "\n";
+			write_output(output.as_deref(), schema.as_bytes())?;
+		},
+		Command::Lsp => {
+			let config = args.config.as_deref();
+			lsp::serve(io::stdin().lock(), io::stdout().lock(), |path| Config::load(&find_config(config, path)?)).exit_with(EXIT_IO)?;
+		},
+		Command::Build(build_args) if build_args.watch => {
+			let input = &build_args.input;
+			let files = || {
+				let mut files = vec![input.clone()];
+				files.extend(find_config(args.config.as_deref(), input));
+				files.append(&mut included_files(input));
+				files
+			};
+			watch(files, || build(build_args, args.config.as_deref()));
+		},
+		Command::Build(build_args) => build(build_args, args.config.as_deref())?
+	}
+	Ok(())
+}
+
+fn build(args: &BuildArgs, config: Option<&Path>) -> Result<(), Failure> {
+	let config = load_config(config, &args.input)?;
+	let instructions = parse(&args.input, &config)?;
+	analyse(&instructions, &config, args.coverage)?;
+
+	let mut terms = instructions_to_terms(&instructions, &config).exit_with(EXIT_SOURCE)?;
+	if args.minimize {
+		let length = terms.len();
+		terms = pla::minimize(terms);
+		eprintln!("Minimized {} rows to {}", length, terms.len());
+	}
+	let rom = |format| rom_words(&terms, &config).and_then(|words| generate_rom(&words, &config, format)).exit_with(EXIT_EXPORT);
+	let data = match args.format {
+		Format::Blueprint => (generate_terms_blueprint(&terms, &config, args.decoration).exit_with(EXIT_EXPORT)? + "\n").into_bytes(),
+		Format::Binary => rom(RomFormat::Binary)?,
+		Format::Ihex => rom(RomFormat::IntelHex)?,
+		Format::Logisim => rom(RomFormat::Logisim)?,
+		Format::Vmem => rom(RomFormat::Vmem)?,
+		Format::Verilog => generate_verilog(&terms, &config, &args.module).into_bytes(),
+		Format::Testbench => {
+			// the expected words go next to the testbench, or into the working directory when it's written to stdout
+			let words_path = args.output.as_ref().map_or_else(|| PathBuf::from(format!("{}_tb.hex", args.module)), |f| f.with_extension("hex"));
+			let (testbench, words) = generate_testbench(&terms, &config, &args.module, &words_path.to_string_lossy()).exit_with(EXIT_EXPORT)?;
+			write_output(Some(&words_path), words.as_bytes())?;
+			testbench.into_bytes()
+		},
+		Format::Markdown => generate_docs(&instructions, &config, DocFormat::Markdown).into_bytes(),
+		Format::Html => generate_docs(&instructions, &config, DocFormat::Html).into_bytes()
+	};
+	write_output(args.output.as_deref(), &data)
+}
+
+/// Runs `action` and then again whenever one of the files returned by `files` is changed, created or removed.
+/// Modification times are polled twice a second, errors are printed and the watch goes on.
+fn watch(files: impl Fn() -> Vec<PathBuf>, mut action: impl FnMut() -> Result<(), Failure>) -> ! {
+	let modified = |files: &[PathBuf]| -> Vec<Option<SystemTime>> {
+		files.iter().map(|f| fs::metadata(f).and_then(|f| f.modified()).ok()).collect()
+	};
+	loop {
+		match action() {
+			Ok(()) => eprintln!("Build finished"),
+			Err(failure) => eprintln!("Error: {}", failure.error)
+		}
+		let watched = files();
+		let times = modified(&watched);
+		eprintln!("Watching {} files for changes", watched.len());
+		while modified(&watched) == times {
+			thread::sleep(Duration::from_millis(500));
 		}
 	}
+}
+
+fn load_config(explicit: Option<&Path>, input: &Path) -> Result<Config, Failure> {
+	Config::load(&find_config(explicit, input).exit_with(EXIT_CONFIG)?).exit_with(EXIT_CONFIG)
+}
+
+fn parse(input: &Path, config: &Config) -> Result<Vec<Instruction>, Failure> {
+	parse_file(input, config).map_err(|error| {
+		let code = if error.is::<SourceErrors>() { EXIT_SOURCE } else { EXIT_IO };
+		Failure { code, error }
+	})
+}
 
-	generate_logic_blueprint(&ink_buffer, width, height)
-}
\ No newline at end of file
+/// Prints overlap warnings and, with `coverage`, the unhandled patterns, returns how many there were.
+fn analyse(instructions: &[Instruction], config: &Config, coverage: bool) -> Result<usize, Failure> {
+	let overlaps = find_overlaps(instructions, config).exit_with(EXIT_SOURCE)?;
+	for overlap in &overlaps {
+		eprintln!("Warning: {}", overlap.report(instructions, config));
+	}
+	let mut warnings = overlaps.len();
+	if coverage {
+		let uncovered = find_uncovered(instructions, config).exit_with(EXIT_SOURCE)?;
+		eprintln!("{} unhandled opcode/counter patterns", uncovered.len());
+		for inputs in &uncovered {
+			eprintln!("  {}", format_inputs(inputs, config));
+		}
+		warnings += uncovered.len();
+	}
+	Ok(warnings)
+}
+
+fn write_output(output: Option<&Path>, data: &[u8]) -> Result<(), Failure> {
+	match output {
+		Some(path) => fs::write(path, data).map_err(|e| format!("Couldn't write '{}': {}", path.display(), e)).exit_with(EXIT_IO),
+		None => io::stdout().write_all(data).exit_with(EXIT_IO)
+	}
+}
+
+/// An explicit path wins, otherwise config.json is looked up next to the input file and then in the working directory.
+fn find_config(explicit: Option<&Path>, input: &Path) -> Result<PathBuf, Error> {
+	if let Some(path) = explicit {
+		return Ok(path.to_owned());
+	}
+	let beside_input = input.parent().unwrap_or(Path::new("")).join("config.json");
+	if beside_input.exists() {
+		return Ok(beside_input);
+	}
+	if Path::new("config.json").exists() {
+		return Ok(PathBuf::from("config.json"));
+	}
+	Err(format!("No config.json next to '{}' or in the working directory, create one with `init` or pass --config", input.display()).into())
+}
@@ -1,11 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
 
-use nom::{IResult, character::complete::{ multispace1, not_line_ending, one_of, space1, digit1}, multi::{many1, separated_list1}, bytes::complete::tag, sequence::{preceded, delimited, terminated, pair, separated_pair}, combinator::{map, opt}, branch::alt};
-use crate::{error::ParseError, Config};
+use nom::{IResult, character::complete::{ multispace1, not_line_ending, none_of, one_of, space0, space1}, multi::{many1, separated_list0, separated_list1}, bytes::complete::tag, sequence::{preceded, delimited, terminated, pair, separated_pair, tuple}, combinator::{map, opt, recognize}, branch::alt};
+use crate::{analysis::micro_operation_names, error::{Error, ParseError, SourceError, SourceErrors}, Config};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum State {
 	True,
+	#[default]
 	False,
 	Any
 }
@@ -23,22 +24,59 @@ pub fn str_to_state_vec(input: &str) -> Result<Vec<State>, ParseError> {
     Ok(result)
 }
 
-impl Default for State {
-	fn default() -> Self {
-		Self::False
-	}
+pub fn state_vec_to_string(state_vec: &[State]) -> String {
+    state_vec.iter().map(|f| match f {
+        State::False => '0',
+        State::True => '1',
+        State::Any => '#'
+    }).collect()
 }
 
-type Opcodes = HashMap<String, Vec<State>>;
+/// Opcode pattern `length` bits long. Plain `1`, `0` and `#` strings are the bits as written, `0b` and `0x` literals are numbers
+/// whose `x` or `#` digits are don't-cares, they're zero extended and may have leading zeros or don't-cares past `length`.
+pub fn parse_opcode_literal(text: &str, name: &str, length: u64) -> Result<Vec<State>, ParseError> {
+    let (digits, digit_length) = if let Some(digits) = text.strip_prefix("0x") {
+        (digits, 4)
+    } else if let Some(digits) = text.strip_prefix("0b") {
+        (digits, 1)
+    } else {
+        let state_vec = str_to_state_vec(text)?;
+        if state_vec.len() as u64 != length {return Err(ParseError::OpcodeLength(name.to_owned()));}
+        return Ok(state_vec);
+    };
+    if digits.is_empty() {return Err(ParseError::Formatting);}
+
+    let mut state_vec = vec![];
+    for ch in digits.chars() {
+        if ch == '#' || ch == 'x' {
+            state_vec.extend(vec![State::Any; digit_length]);
+            continue;
+        }
+        let digit = ch.to_digit(1 << digit_length).ok_or(ParseError::Formatting)?;
+        state_vec.extend((0..digit_length).rev().map(|b| if digit >> b & 1 == 1 { State::True } else { State::False }));
+    }
+    let length = length as usize;
+    if state_vec.len() > length {
+        let excess = state_vec.len() - length;
+        if state_vec[..excess].contains(&State::True) {return Err(ParseError::OpcodeLength(name.to_owned()));}
+        state_vec.drain(..excess);
+    }
+    let mut result = vec![State::False; length - state_vec.len()];
+    result.append(&mut state_vec);
+    Ok(result)
+}
 
 
 #[derive(Debug, Clone)]
 pub struct Operation {
-    pub counter: u32,
-    pub micro_operations: Vec<i64>
+    /// Counter bits the operation fires on, most significant first.
+    pub counter: Vec<State>,
+    pub micro_operations: Vec<u64>,
+    /// Condition on every flag, `State::Any` for flags the operation doesn't depend on.
+    pub flags: Vec<State>
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Instruction {
     pub opcodes: Vec<Vec<State>>,
     pub operations: Vec<Operation>
@@ -48,9 +86,95 @@ impl Instruction {
     pub fn new() -> Self {
         Self { opcodes: vec![], operations: vec![] }
     }
+
+    /// Header in source form, opcodes that match anything are left out.
+    pub fn header(&self, config: &Config) -> String {
+        format_header(&self.opcodes, config)
+    }
+}
+
+fn format_header(opcodes: &[Vec<State>], config: &Config) -> String {
+    let opcodes = config.opcodes.iter().zip(opcodes)
+        .filter(|(_, value)| value.iter().any(|f| *f != State::Any))
+        .map(|(opcode, value)| format!("{}={}", opcode.0, state_vec_to_string(value)))
+        .collect::<Vec<String>>();
+    format!("[{}]", opcodes.join(" "))
+}
+
+/// Counter in source form, decimal for a single step, `*` for every step and the bit pattern otherwise.
+fn format_counter(counter: &[State]) -> String {
+    if counter.iter().all(|f| *f == State::Any) && !counter.is_empty() {return "*".to_owned();}
+    if counter.contains(&State::Any) {return state_vec_to_string(counter);}
+    counter.iter().fold(0u64, |value, f| value << 1 | (*f == State::True) as u64).to_string()
+}
+
+/// Flag conditions in source form, unnamed flags are written with the microcode mapped to the matching negative index.
+fn format_flags(flags: &[State], config: &Config) -> Result<Vec<String>, ParseError> {
+    let mut conditions = vec![];
+    for (index, state) in flags.iter().enumerate() {
+        if *state == State::Any {continue;}
+        if let Some(name) = config.flags.get(index) {
+            conditions.push(format!("?{}={}", name, state_vec_to_string(&[*state])));
+            continue;
+        }
+        let i = 2 * (config.flags_bit_length as i64 - index as i64) - (*state == State::True) as i64;
+        let name = config.microcode_map.iter().filter(|f| *f.1 == -i).map(|f| f.0).min();
+        conditions.push(name.ok_or(ParseError::MissingFlag(index.to_string()))?.clone());
+    }
+    Ok(conditions)
+}
+
+/// Source text for the instructions, one operation line per operation. Operations that write nothing are left out.
+pub fn format_instructions(instructions: &[Instruction], config: &Config) -> Result<String, ParseError> {
+    let mut blocks = vec![];
+    for instruction in instructions {
+        let mut lines = vec![instruction.header(config)];
+        for operation in instruction.operations.iter().filter(|f| !f.micro_operations.is_empty()) {
+            let mut micro_operations = operation.micro_operations.clone();
+            micro_operations.sort();
+            micro_operations.dedup();
+            let mut tokens = vec![format_counter(&operation.counter)];
+            tokens.append(&mut format_flags(&operation.flags, config)?);
+            tokens.append(&mut micro_operation_names(&micro_operations, config));
+            lines.push(tokens.join(" "));
+        }
+        blocks.push(lines.join("\n"));
+    }
+    Ok(blocks.join("\n\n") + "\n")
+}
+
+/// Error pointing at the part of the source it was raised for, `span` is always a subslice of the parsed input.
+struct Spanned<'a> {
+    error: ParseError,
+    span: &'a str
+}
+
+impl<'a> Spanned<'a> {
+    fn new(error: ParseError, span: &'a str) -> Self {
+        Self { error, span }
+    }
+
+    /// Points at the token starting at `input`.
+    fn at(error: ParseError, input: &'a str) -> Self {
+        let length = input.find(char::is_whitespace).unwrap_or(input.len());
+        Self { error, span: &input[..length] }
+    }
+
+    fn locate(self, source: &str) -> SourceError {
+        let offset = self.span.as_ptr() as usize - source.as_ptr() as usize;
+        SourceError::new(self.error, source, offset, self.span.len())
+    }
 }
 
-fn parse_comment(input: &str) -> IResult<&str, &str> {
+/// Remaining input at the point where a nom parser gave up.
+fn failed_at<'a>(error: nom::Err<nom::error::Error<&'a str>>, input: &'a str) -> &'a str {
+    match error {
+        nom::Err::Error(error) | nom::Err::Failure(error) => error.input,
+        nom::Err::Incomplete(_) => input
+    }
+}
+
+pub(crate) fn parse_comment(input: &str) -> IResult<&str, &str> {
     preceded(tag("//"), not_line_ending)(input)
 }
 
@@ -61,11 +185,8 @@ fn parse_multispace(input: &str) -> IResult<&str, String> {
     )(input)
 }
 
-fn parse_word(input: &str) -> IResult<&str, String> {
-    map(
-        many1(one_of("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_-!1234567890")),
-        |f| f.iter().collect::<String>()
-    )(input)
+fn parse_word(input: &str) -> IResult<&str, &str> {
+    recognize(many1(one_of("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_-!1234567890")))(input)
 }
 
 fn parse_space(input: &str) -> IResult<&str, String> {
@@ -75,101 +196,870 @@ fn parse_space(input: &str) -> IResult<&str, String> {
     )(input)
 }
 
+#[derive(Clone)]
+pub(crate) enum Token<'a> {
+    Condition(&'a str, &'a str),
+    Assignment(&'a str, &'a str),
+    Call(&'a str, Vec<&'a str>),
+    Word(&'a str)
+}
+
+fn parse_condition(input: &str) -> IResult<&str, (&str, &str)> {
+    preceded(
+        tag("?"),
+        separated_pair(parse_word, tag("="), recognize(one_of("01")))
+    )(input)
+}
+
+/// `$name` inside a macro body, the slice keeps the `$`.
+fn parse_parameter(input: &str) -> IResult<&str, &str> {
+    recognize(pair(tag("$"), parse_word))(input)
+}
+
+fn parse_names<'a, F>(item: F) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<&'a str>>
+where F: FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    map(
+        opt(delimited(
+            terminated(tag("("), space0),
+            separated_list0(tuple((space0, tag(","), space0)), item),
+            preceded(space0, tag(")"))
+        )),
+        Option::unwrap_or_default
+    )
+}
+
+fn parse_call(input: &str) -> IResult<&str, (&str, Vec<&str>)> {
+    preceded(tag("@"), pair(parse_word, parse_names(alt((parse_parameter, parse_word)))))(input)
+}
+
+fn parse_token(input: &str) -> IResult<&str, Token<'_>> {
+    alt((
+        map(parse_condition, |(name, value)| Token::Condition(name, value)),
+        map(parse_call, |(name, arguments)| Token::Call(name, arguments)),
+        map(separated_pair(parse_word, tag("="), alt((parse_parameter, parse_word))), |(name, value)| Token::Assignment(name, value)),
+        map(alt((parse_parameter, parse_word)), Token::Word)
+    ))(input)
+}
+
+/// Decimal counter, range or pattern, `*` for every step or `+`/`_` for the counter after the previous line's.
 fn parse_counter(input: &str) -> IResult<&str, &str> {
-    digit1(input)
+    alt((recognize(many1(one_of("0123456789#-"))), tag("*"), tag("+"), tag("_")))(input)
 }
 
-fn parse_opcode(input: &str) -> IResult<&str, (String, String)> {
+/// Literal, config value name or constant, resolved once every constant is known.
+fn parse_opcode_value(input: &str) -> IResult<&str, &str> {
+    recognize(many1(alt((parse_word, tag("#")))))(input)
+}
+
+fn parse_opcode(input: &str) -> IResult<&str, (&str, &str)> {
     separated_pair(
         terminated(parse_word, opt(parse_space)), 
         tag("="),
         preceded(
             opt(parse_space),
-            map(
-                many1(one_of("10#")),
-                |f| f.iter().collect::<String>()
-            )
+            parse_opcode_value
         )
     )(input)
 }
 
-fn parse_opcodes<'a>(input: &'a str, config: &'a Config) -> Result<(&'a str, Opcodes), ParseError> {
-    let result = delimited(
-        terminated(tag("["), opt(parse_space)),
-        opt(separated_list1(parse_space, parse_opcode)),
-        preceded(opt(parse_space), tag("]"))
-    )(input).map_err(|_| ParseError::OpcodeFormatting)?;
+/// `#` followed by a letter starts a directive, otherwise it's a counter pattern like `#01`.
+pub(crate) fn is_directive(input: &str) -> bool {
+    input.strip_prefix('#').and_then(|f| f.chars().next()).is_some_and(|f| f.is_ascii_alphabetic())
+}
+
+/// Rest of the input from the end of the current line, parsing resumes there after an error.
+fn skip_line(input: &str) -> &str {
+    &input[input.find('\n').unwrap_or(input.len())..]
+}
+
+/// Operation line as written, it's resolved against the config once every macro is known.
+pub(crate) struct Line<'a> {
+    pub(crate) counter: &'a str,
+    pub(crate) tokens: Vec<Token<'a>>
+}
 
-    let mut opcodes = HashMap::new();
-    if let Some(result) = result.1 {
-        for opcode_pair in result.iter() {
-            let opcode_value = str_to_state_vec(&opcode_pair.1)?;
-            let opcode_name = opcode_pair.0.clone();
-            if opcode_value.len() as u64 != config.opcodes.iter().find(|p| p.0 == opcode_name).ok_or(ParseError::MissingOpcode(opcode_name.clone()))?.1 {return Err(ParseError::OpcodeLength(opcode_name));}
-            opcodes.insert(opcode_name, opcode_value);
+struct Macro<'a> {
+    parameters: Vec<&'a str>,
+    lines: Vec<Line<'a>>
+}
+
+struct RawInstruction<'a> {
+    /// Opcode names and values as written in the header.
+    opcodes: Vec<(&'a str, &'a str)>,
+    lines: Vec<Line<'a>>,
+    valid: bool
+}
+
+/// Opcode names and values between `[` and `]`.
+pub(crate) fn parse_header(input: &str) -> IResult<&str, Vec<(&str, &str)>> {
+    map(
+        delimited(
+            terminated(tag("["), opt(parse_space)),
+            opt(separated_list1(parse_space, parse_opcode)),
+            preceded(opt(parse_space), tag("]"))
+        ),
+        Option::unwrap_or_default
+    )(input)
+}
+
+/// Returns `None` if the header couldn't be parsed.
+fn parse_opcodes<'a>(input: &'a str, errors: &mut Vec<Spanned<'a>>) -> Option<(&'a str, Vec<(&'a str, &'a str)>)> {
+    match parse_header(input) {
+        Ok(result) => Some(result),
+        Err(e) => {
+            errors.push(Spanned::at(ParseError::OpcodeFormatting, failed_at(e, input)));
+            None
+        }
+    }
+}
+
+pub(crate) fn parse_line(input: &str) -> IResult<&str, Line<'_>> {
+    map(
+        pair(terminated(parse_counter, parse_space), separated_list1(parse_space, parse_token)),
+        |(counter, tokens)| Line { counter, tokens }
+    )(input)
+}
+
+/// Returns `None` if the line couldn't be parsed.
+fn parse_operation_line<'a>(input: &'a str, errors: &mut Vec<Spanned<'a>>) -> Option<(&'a str, Line<'a>)> {
+    match parse_line(input) {
+        Ok(result) => Some(result),
+        Err(e) => {
+            errors.push(Spanned::at(ParseError::InstructionFormatting, failed_at(e, input)));
+            None
+        }
+    }
+}
+
+/// Operation lines up to the next instruction, directive or the end of the input.
+fn parse_operation_lines<'a>(mut input: &'a str, errors: &mut Vec<Spanned<'a>>) -> (&'a str, Vec<Line<'a>>) {
+    let mut lines = vec![];
+    loop {
+        if input.is_empty() {break;}
+        let rest = match parse_multispace(input) {
+            Ok((rest, _)) => rest,
+            Err(e) => {
+                errors.push(Spanned::at(ParseError::InstructionFormatting, failed_at(e, input)));
+                input = skip_line(input);
+                continue;
+            }
+        };
+        input = rest;
+        if input.starts_with('[') || is_directive(input) || input.is_empty() {break;}
+
+        match parse_operation_line(input, errors) {
+            Some((rest, line)) => {
+                input = rest;
+                lines.push(line);
+            },
+            None => input = skip_line(input)
         }
     }
-    
-    Ok((result.0, opcodes))
+    (input, lines)
 }
 
-fn parse_operation_line<'a>(input: &'a str, config: &'a Config) -> Result<(&'a str, Operation), ParseError> {
-    let result = pair(terminated(parse_counter, parse_space), separated_list1(parse_space, parse_word))(input).map_err(|_| ParseError::InstructionFormatting)?;
-    let counter = result.1.0.parse::<u32>().map_err(|_| ParseError::CounterFormatting)?;
-    let micro_operations = result.1.1.iter()
-        .map(|f| 
-            config.microcode_map.get(f)
-            .ok_or(ParseError::MissingInstruction(f.clone())).map(|op| op.clone()))
-        .collect::<Result<Vec<i64>, ParseError>>()?;
-    Ok((result.0, Operation { counter, micro_operations }))
+fn parse_instruction<'a>(input: &'a str, errors: &mut Vec<Spanned<'a>>) -> (&'a str, RawInstruction<'a>) {
+    let error_count = errors.len();
+    let (rest, opcodes) = parse_opcodes(input, errors).unwrap_or((skip_line(input), vec![]));
+    let (rest, lines) = parse_operation_lines(rest, errors);
+    (rest, RawInstruction { opcodes, lines, valid: errors.len() == error_count })
 }
 
-pub fn parse_instruction(input: &str, config: &Config) -> Result<(String, Instruction), ParseError> {
-    let mut input = input.to_owned();
+/// `#const NAME = VALUE`, usable as an opcode value anywhere in the source.
+pub(crate) fn parse_constant(input: &str) -> IResult<&str, (&str, &str)> {
+    preceded(
+        pair(tag("#const"), space1),
+        separated_pair(parse_word, tuple((space0, tag("="), space0)), parse_opcode_value)
+    )(input)
+}
 
-    let mut instruction = Instruction::new();
-    let (rest, opcodes) = parse_opcodes(&input, config)?;
-    for opcode in &config.opcodes {
-        if let Some(value) = opcodes.get(&opcode.0) {
-            instruction.opcodes.push(value.clone());
+pub(crate) fn parse_macro_header(input: &str) -> IResult<&str, (&str, Vec<&str>)> {
+    preceded(
+        pair(tag("#macro"), space1),
+        pair(parse_word, parse_names(parse_word))
+    )(input)
+}
+
+/// `#macro NAME(parameters)` followed by operation lines and `#end`, the counters in the body are relative to the call.
+fn parse_macro<'a>(input: &'a str, errors: &mut Vec<Spanned<'a>>) -> (&'a str, Option<(&'a str, Macro<'a>)>) {
+    let error_count = errors.len();
+    let (rest, (name, parameters)) = match parse_macro_header(input) {
+        Ok(result) => result,
+        Err(e) => {
+            errors.push(Spanned::at(ParseError::MacroFormatting, failed_at(e, input)));
+            (skip_line(input), (Spanned::at(ParseError::MacroFormatting, input).span, vec![]))
+        }
+    };
+
+    let (rest, lines) = parse_operation_lines(rest, errors);
+    let Some(rest) = rest.strip_prefix("#end") else {
+        errors.push(Spanned::new(ParseError::UnterminatedMacro(name.to_owned()), name));
+        return (rest, None);
+    };
+    if errors.len() > error_count {
+        return (rest, None);
+    }
+    (rest, Some((name, Macro { parameters, lines })))
+}
+
+fn set_flag<'a>(flags: &mut [State], index: usize, state: State, span: &'a str, config: &Config, errors: &mut Vec<Spanned<'a>>) {
+    if flags[index] != State::Any && flags[index] != state {
+        let name = config.flags.get(index).cloned().unwrap_or(index.to_string());
+        errors.push(Spanned::new(ParseError::FlagConflict(name), span));
+    }
+    flags[index] = state;
+}
+
+/// Counter steps matched by an operation line.
+struct Steps {
+    patterns: Vec<Vec<State>>,
+    first: u32,
+    last: u32
+}
+
+/// Patterns over `length` counter bits matching every step from `first` to `last`, one for each aligned block of steps.
+fn range_patterns(mut first: u64, last: u64, length: u64) -> Vec<Vec<State>> {
+    let mut patterns = vec![];
+    while first <= last {
+        let mut size = 0;
+        while size < length && first.is_multiple_of(2 << size) && first + (2 << size) - 1 <= last {
+            size += 1;
+        }
+        patterns.push((0..length).rev().map(|b| {
+            if b < size { State::Any } else if first >> b & 1 == 1 { State::True } else { State::False }
+        }).collect());
+        first += 1 << size;
+    }
+    patterns
+}
+
+/// Turns the parsed lines into operations, expanding macro calls along the way.
+struct Resolver<'a, 'c> {
+    config: &'c Config,
+    macros: HashMap<&'a str, Macro<'a>>,
+    constants: HashMap<&'a str, &'a str>,
+    stack: Vec<&'a str>,
+    /// Counter of the outermost macro call being expanded, counter overflows inside macros are reported there.
+    call_site: &'a str,
+    /// Header of the instruction being resolved.
+    instruction: String,
+    errors: Vec<Spanned<'a>>
+}
+
+impl<'a> Resolver<'a, '_> {
+    /// Opcode patterns of a header in layout order, opcodes that aren't given or can't be resolved match anything.
+    /// A value is looked up in the opcode's config values, then in the constants, and read as a literal otherwise.
+    fn opcodes(&mut self, header: &[(&'a str, &'a str)]) -> Vec<Vec<State>> {
+        let config = self.config;
+        let mut opcodes: Vec<Vec<State>> = config.opcodes.iter().map(|f| vec![State::Any; f.1 as usize]).collect();
+        for (name, value) in header {
+            let Some(index) = config.opcodes.iter().position(|f| f.0 == *name) else {
+                self.errors.push(Spanned::new(ParseError::MissingOpcode(name.to_string()), name));
+                continue;
+            };
+            if let Some(state_vec) = config.opcode_values.get(*name).and_then(|f| f.get(*value)) {
+                opcodes[index] = state_vec.clone();
+                continue;
+            }
+            let literal = match self.constants.get(value) {
+                Some(literal) => literal,
+                None if value.starts_with(|f: char| f.is_ascii_alphabetic() || f == '_') => {
+                    self.errors.push(Spanned::new(ParseError::MissingConstant(value.to_string()), value));
+                    continue;
+                },
+                None => value
+            };
+            match parse_opcode_literal(literal, name, config.opcodes[index].1) {
+                Ok(state_vec) => opcodes[index] = state_vec,
+                Err(e) => self.errors.push(Spanned::new(e, value))
+            }
+        }
+        opcodes
+    }
+
+    /// Replaces a `$parameter` with the argument bound to it.
+    fn substitute(&mut self, text: &'a str, bindings: &HashMap<&'a str, &'a str>) -> Option<&'a str> {
+        let Some(name) = text.strip_prefix('$') else {return Some(text);};
+        let value = bindings.get(name).copied();
+        if value.is_none() {
+            self.errors.push(Spanned::new(ParseError::MissingParameter(name.to_owned()), text));
+        }
+        value
+    }
+
+    /// Counter steps of the line moved by `offset`, `+` and `_` stand for `next`. `4-7` is a range, `1#0#` a pattern over the counter bits
+    /// and `*` every step, patterns are moved like numbers so they may turn into several.
+    fn counter(&mut self, line: &Line<'a>, offset: u32, next: u32) -> Steps {
+        let length = self.config.counter_bit_length;
+        let max_counter = 2u64.pow(length as u32) - 1;
+        let text = if line.counter == "*" { "#".repeat(length as usize) } else { line.counter.to_owned() };
+        let counter = if text == "+" || text == "_" {
+            Some((next as u64, next as u64, None))
+        } else if text.contains('#') {
+            str_to_state_vec(&text).ok().filter(|f| f.len() as u64 == length).map(|pattern| {
+                let first = pattern.iter().fold(0, |value, f| value << 1 | (*f == State::True) as u64);
+                let last = pattern.iter().fold(0, |value, f| value << 1 | (*f != State::False) as u64);
+                (first, last, Some(pattern))
+            })
+        } else if let Some((first, last)) = text.split_once('-') {
+            first.parse::<u64>().ok().zip(last.parse::<u64>().ok()).filter(|(first, last)| first <= last).map(|(first, last)| (first, last, None))
         } else {
-            let mut default_state_vec = vec![];
-            for _ in 0..opcode.1 {
-                default_state_vec.push(State::Any);
+            text.parse::<u64>().ok().map(|counter| (counter, counter, None))
+        };
+
+        let Some((first, last, pattern)) = counter else {
+            self.errors.push(Spanned::new(ParseError::CounterFormatting, line.counter));
+            return Steps { patterns: vec![], first: offset, last: offset };
+        };
+        let (first, last) = (first + offset as u64, last + offset as u64);
+        if last > max_counter {
+            let span = if self.stack.is_empty() { line.counter } else { self.call_site };
+            self.errors.push(Spanned::new(ParseError::CounterOverflow(self.instruction.clone()), span));
+            return Steps { patterns: vec![], first: first.min(u32::MAX as u64) as u32, last: last.min(u32::MAX as u64) as u32 };
+        }
+
+        let patterns = match pattern {
+            Some(pattern) if offset == 0 => vec![pattern],
+            Some(pattern) => {
+                let bits = |value: u64| (0..length).rev().map(|b| value >> b & 1 == 1).collect::<Vec<bool>>();
+                let values: Vec<u64> = (first..=last).filter(|f| {
+                    bits(f - offset as u64).iter().zip(&pattern).all(|(bit, state)| *state == State::Any || *bit == (*state == State::True))
+                }).collect();
+                let mut patterns = vec![];
+                for run in values.chunk_by(|a, b| a + 1 == *b) {
+                    patterns.append(&mut range_patterns(run[0], run[run.len() - 1], length));
+                }
+                patterns
+            },
+            None => range_patterns(first, last, length)
+        };
+        Steps { patterns, first: first as u32, last: last as u32 }
+    }
+
+    /// Resolves one line with its counter moved by `offset`, a macro call expands to the macro body moved to the call's counter.
+    /// `next` is the counter an implicitly numbered line gets, it's moved past the line or the expanded macro body.
+    /// Microcodes mapped to negative indices are flag conditions, `-(2k+1)` means the k-th flag from the right is set, `-(2k+2)` that it's clear.
+    fn expand(&mut self, line: &Line<'a>, offset: u32, next: &mut u32, bindings: &HashMap<&'a str, &'a str>, operations: &mut Vec<Operation>) {
+        let steps = self.counter(line, offset, *next);
+        *next = steps.last - offset + 1;
+
+        if let Some(Token::Call(name, arguments)) = line.tokens.iter().find(|f| matches!(f, Token::Call(_, _))) {
+            if line.tokens.len() > 1 {
+                self.errors.push(Spanned::new(ParseError::MacroCallFormatting, name));
+                return;
+            }
+            let mut values = vec![];
+            for argument in arguments {
+                values.push(self.substitute(argument, bindings));
+            }
+            if self.stack.contains(name) {
+                self.errors.push(Spanned::new(ParseError::MacroRecursion(name.to_string()), name));
+                return;
+            }
+            let Some(definition) = self.macros.get(name) else {
+                self.errors.push(Spanned::new(ParseError::MissingMacro(name.to_string()), name));
+                return;
+            };
+            if definition.parameters.len() != arguments.len() {
+                self.errors.push(Spanned::new(ParseError::MacroArguments(name.to_string(), definition.parameters.len()), name));
+                return;
+            }
+            if steps.first != steps.last {
+                self.errors.push(Spanned::new(ParseError::CounterFormatting, line.counter));
+                return;
+            }
+            let counter = steps.first;
+            let Some(values) = values.into_iter().collect::<Option<Vec<&str>>>() else {return;};
+            let bindings: HashMap<&str, &str> = definition.parameters.iter().copied().zip(values).collect();
+
+            // the body is borrowed from `self.macros`, take it out for the expansion so `self` stays usable
+            let definition = self.macros.remove(name).unwrap();
+            if self.stack.is_empty() {
+                self.call_site = line.counter;
+            }
+            self.stack.push(name);
+            let mut body_next = 0;
+            for body_line in &definition.lines {
+                self.expand(body_line, counter, &mut body_next, &bindings, operations);
+            }
+            self.stack.pop();
+            *next = counter - offset + body_next.max(1);
+            self.macros.insert(name, definition);
+            return;
+        }
+
+        let config = self.config;
+        let mut micro_operations = vec![];
+        let mut flags = vec![State::Any; config.flags_bit_length as usize];
+        let mut assigned_fields = vec![];
+        for token in &line.tokens {
+            match token {
+                Token::Assignment(name, value) => {
+                    let Some(value) = self.substitute(value, bindings) else {continue;};
+                    let Some(field) = config.fields.iter().find(|f| f.name == *name) else {
+                        self.errors.push(Spanned::new(ParseError::MissingField(name.to_string()), name));
+                        continue;
+                    };
+                    if assigned_fields.contains(name) {
+                        self.errors.push(Spanned::new(ParseError::FieldConflict(name.to_string()), name));
+                        continue;
+                    }
+                    assigned_fields.push(name);
+                    let number = field.values.get(value).copied().or(value.parse::<u64>().ok());
+                    match number {
+                        Some(number) if field.length >= 64 || number >> field.length == 0 => {
+                            micro_operations.extend((0..field.length.min(64)).filter(|b| number >> b & 1 == 1).map(|b| field.index + b));
+                        },
+                        _ => self.errors.push(Spanned::new(ParseError::FieldValue(name.to_string(), value.to_owned()), value))
+                    }
+                },
+                Token::Condition(name, value) => {
+                    let Some(index) = config.flags.iter().position(|f| f == name) else {
+                        self.errors.push(Spanned::new(ParseError::MissingFlag(name.to_string()), name));
+                        continue;
+                    };
+                    let state = if *value == "1" { State::True } else { State::False };
+                    set_flag(&mut flags, index, state, name, config, &mut self.errors);
+                },
+                Token::Word(word) => {
+                    let Some(word) = self.substitute(word, bindings) else {continue;};
+                    match config.microcode_map.get(word) {
+                        Some(micro_operation) if *micro_operation >= 0 => micro_operations.push(*micro_operation as u64),
+                        Some(micro_operation) => {
+                            let i = -micro_operation as u64;
+                            if i > config.flags_bit_length * 2 {continue;}
+                            let index = (config.flags_bit_length - i.div_ceil(2)) as usize;
+                            let state = if i % 2 == 1 { State::True } else { State::False };
+                            set_flag(&mut flags, index, state, word, config, &mut self.errors);
+                        },
+                        None => self.errors.push(Spanned::new(ParseError::MissingInstruction(word.to_owned()), word))
+                    }
+                },
+                Token::Call(_, _) => {}
             }
-            instruction.opcodes.push(default_state_vec);
+        }
+        for counter in steps.patterns {
+            operations.push(Operation { counter, micro_operations: micro_operations.clone(), flags: flags.clone() });
         }
     }
-    input = rest.to_owned();
+}
 
-    loop {
-        let (rest, _) = parse_multispace(&input).map_err(|_| ParseError::InstructionFormatting)?;
-        if rest.starts_with("[") || rest.is_empty() {break;}
-        input = rest.to_owned();
-
-        let (rest, operation) = parse_operation_line(&input, config)?;
-        input = rest.to_owned();
-        if operation.counter > 2u32.pow(config.counter_bit_length as u32) - 1 {return Err(ParseError::CounterOverflow);}
-        instruction.operations.push(operation);
-        if input.is_empty() {break;}
+/// `#include "path"`, the path is relative to the including file.
+pub(crate) fn parse_include(input: &str) -> IResult<&str, &str> {
+    preceded(
+        pair(tag("#include"), space1),
+        delimited(tag("\""), recognize(many1(none_of("\"\r\n"))), tag("\""))
+    )(input)
+}
+
+struct SourceFile {
+    path: Option<PathBuf>,
+    canonical: Option<PathBuf>,
+    text: String
+}
+
+/// The root source and every file it includes, all read before parsing so spans can point into any of them.
+struct Sources {
+    files: Vec<SourceFile>,
+    /// File index for the `(file, offset)` of each include path that could be read.
+    includes: HashMap<(usize, usize), usize>,
+    /// Include errors with the file and offset they're at.
+    errors: Vec<(usize, usize, SourceError)>,
+    /// Included paths that couldn't be read.
+    missing: Vec<PathBuf>
+}
+
+impl Sources {
+    fn new(text: &str, path: Option<&Path>) -> Self {
+        let root = SourceFile {
+            path: path.map(Path::to_owned),
+            canonical: path.and_then(|f| fs::canonicalize(f).ok()),
+            text: text.to_owned()
+        };
+        let mut sources = Self { files: vec![root], includes: HashMap::new(), errors: vec![], missing: vec![] };
+        let mut stack = sources.files[0].canonical.iter().cloned().collect();
+        sources.load(0, &mut stack);
+        sources
+    }
+
+    /// Reads the files included by `index` and recursively theirs, a file included more than once is read once.
+    /// `stack` holds the files being loaded, including one of them again is a cycle.
+    fn load(&mut self, index: usize, stack: &mut Vec<PathBuf>) {
+        let text = &self.files[index].text;
+        let mut directives = vec![];
+        for line in text.split_inclusive('\n') {
+            if let Ok((_, path)) = parse_include(line.trim_start()) {
+                directives.push((path.as_ptr() as usize - text.as_ptr() as usize, path.to_owned()));
+            }
+        }
+        let directory = self.files[index].path.as_ref().and_then(|f| f.parent()).map(Path::to_owned).unwrap_or_default();
+
+        for (offset, path) in directives {
+            let full_path = directory.join(&path);
+            let file = fs::canonicalize(&full_path).and_then(|canonical| Ok((fs::read_to_string(&full_path)?, canonical)));
+            let (text, canonical) = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    self.error(index, offset, &path, ParseError::IncludeFile(path.clone(), e.to_string()));
+                    self.missing.push(full_path);
+                    continue;
+                }
+            };
+            if stack.contains(&canonical) {
+                self.error(index, offset, &path, ParseError::IncludeCycle(path.clone()));
+                continue;
+            }
+            if let Some(included) = self.files.iter().position(|f| f.canonical.as_ref() == Some(&canonical)) {
+                self.includes.insert((index, offset), included);
+                continue;
+            }
+
+            self.files.push(SourceFile { path: Some(full_path), canonical: Some(canonical.clone()), text });
+            let included = self.files.len() - 1;
+            self.includes.insert((index, offset), included);
+            stack.push(canonical);
+            self.load(included, stack);
+            stack.pop();
+        }
+    }
+
+    fn error(&mut self, index: usize, offset: usize, span: &str, error: ParseError) {
+        let file = &self.files[index];
+        let mut error = SourceError::new(error, &file.text, offset, span.len());
+        error.path = file.path.clone();
+        self.errors.push((index, offset, error));
+    }
+
+    /// File index and offset of a span.
+    fn find(&self, span: &str) -> (usize, usize) {
+        let pointer = span.as_ptr() as usize;
+        self.files.iter().enumerate().find_map(|(index, file)| {
+            let start = file.text.as_ptr() as usize;
+            (start..=start + file.text.len()).contains(&pointer).then(|| (index, pointer - start))
+        }).unwrap_or_default()
     }
-    Ok((input, instruction))
 }
 
-pub fn parse_instructions(input: &str, config: &Config) -> Result<Vec<Instruction>, ParseError> {
-    let mut input = input.to_owned();
+/// Everything parsed out of the sources before macro calls are resolved.
+#[derive(Default)]
+struct Items<'a> {
+    instructions: Vec<RawInstruction<'a>>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    constants: HashMap<&'a str, &'a str>,
+    errors: Vec<Spanned<'a>>,
+    /// Files already parsed, an included file is parsed where it's first included.
+    parsed: Vec<usize>
+}
+
+fn parse_file_items<'a>(sources: &'a Sources, index: usize, items: &mut Items<'a>) {
+    items.parsed.push(index);
+    let source = sources.files[index].text.as_str();
+    let mut input = source;
+    if let Ok((rest, _)) = parse_multispace(input) {
+        input = rest;
+    }
+
+    while !input.is_empty() {
+        if input.starts_with("#macro") {
+            let (rest, definition) = parse_macro(input, &mut items.errors);
+            input = rest;
+            if let Some((name, definition)) = definition {
+                if items.macros.contains_key(name) {
+                    items.errors.push(Spanned::new(ParseError::DuplicateMacro(name.to_owned()), name));
+                }
+                items.macros.insert(name, definition);
+            }
+        } else if input.starts_with("#const") {
+            match parse_constant(input) {
+                Ok((rest, (name, value))) => {
+                    input = rest;
+                    if items.constants.contains_key(name) {
+                        items.errors.push(Spanned::new(ParseError::DuplicateConstant(name.to_owned()), name));
+                    } else {
+                        items.constants.insert(name, value);
+                    }
+                },
+                Err(e) => {
+                    items.errors.push(Spanned::at(ParseError::ConstantFormatting, failed_at(e, input)));
+                    input = skip_line(input);
+                }
+            }
+        } else if input.starts_with("#include") {
+            match parse_include(input) {
+                Ok((rest, path)) => {
+                    input = rest;
+                    let included = sources.includes.get(&(index, path.as_ptr() as usize - source.as_ptr() as usize));
+                    if let Some(included) = included.filter(|f| !items.parsed.contains(f)) {
+                        parse_file_items(sources, *included, items);
+                    }
+                },
+                Err(e) => {
+                    items.errors.push(Spanned::at(ParseError::IncludeFormatting, failed_at(e, input)));
+                    input = skip_line(input);
+                }
+            }
+        } else if is_directive(input) {
+            let directive = Spanned::at(ParseError::Formatting, input).span;
+            items.errors.push(Spanned::new(ParseError::UnknownDirective(directive.to_owned()), directive));
+            input = skip_line(input);
+        } else {
+            let (rest, instruction) = parse_instruction(input, &mut items.errors);
+            input = rest;
+            items.instructions.push(instruction);
+        }
+        if let Ok((rest, _)) = parse_multispace(input) {
+            input = rest;
+        }
+    }
+}
+
+fn parse_sources(mut sources: Sources, config: &Config) -> Result<Vec<Instruction>, SourceErrors> {
+    let mut errors: Vec<(usize, usize, SourceError)> = std::mem::take(&mut sources.errors);
+    let mut items = Items::default();
+    parse_file_items(&sources, 0, &mut items);
+
+    let mut resolver = Resolver { config, macros: items.macros, constants: items.constants, stack: vec![], call_site: "", instruction: String::new(), errors: items.errors };
     let mut instructions = vec![];
-    if let Ok((rest, _)) = parse_multispace(&input) {
-        input = rest.to_owned();
+    for raw_instruction in items.instructions {
+        let error_count = resolver.errors.len();
+        let mut operations = vec![];
+        let mut next = 0;
+        let opcodes = resolver.opcodes(&raw_instruction.opcodes);
+        // named as written, the resolved patterns are empty if the header has an error
+        let header: Vec<String> = raw_instruction.opcodes.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        resolver.instruction = format!("[{}]", header.join(" "));
+        for line in &raw_instruction.lines {
+            resolver.expand(line, 0, &mut next, &HashMap::new(), &mut operations);
+        }
+        if raw_instruction.valid && resolver.errors.len() == error_count {
+            instructions.push(Instruction { opcodes, operations });
+        }
+    }
+
+    if resolver.errors.is_empty() && errors.is_empty() {
+        return Ok(instructions);
+    }
+    for error in resolver.errors {
+        let (index, offset) = sources.find(error.span);
+        let file = &sources.files[index];
+        let mut error = error.locate(&file.text);
+        error.path = file.path.clone();
+        errors.push((index, offset, error));
+    }
+    // a mistake in a macro body is found once per call
+    errors.sort_by_key(|f| (f.0, f.1));
+    errors.dedup_by(|a, b| a.0 == b.0 && a.1 == b.1 && a.2.length == b.2.length && a.2.error.to_string() == b.2.error.to_string());
+    Err(SourceErrors(errors.into_iter().map(|f| f.2).collect()))
+}
+
+/// Parsing resumes after an error at the next operation line, instruction or directive, so every error in the source is reported.
+/// Macros may be used before they're defined. Included files are resolved relative to the working directory.
+pub fn parse_instructions(source: &str, config: &Config) -> Result<Vec<Instruction>, SourceErrors> {
+    parse_source(source, None, config)
+}
+
+/// Same as `parse_instructions` for a source read from `path`, included files are resolved relative to it and errors name the file they're in.
+pub fn parse_source(source: &str, path: Option<&Path>, config: &Config) -> Result<Vec<Instruction>, SourceErrors> {
+    parse_sources(Sources::new(source, path), config)
+}
+
+pub fn parse_file(path: &Path, config: &Config) -> Result<Vec<Instruction>, Error> {
+    let source = fs::read_to_string(path).map_err(|e| format!("Couldn't read '{}': {}", path.display(), e))?;
+    Ok(parse_source(&source, Some(path), config)?)
+}
+
+/// Files included by `path` directly or through other includes, including the ones that couldn't be read.
+pub fn included_files(path: &Path) -> Vec<PathBuf> {
+    let Ok(source) = fs::read_to_string(path) else {return vec![];};
+    source_included_files(&source, path)
+}
+
+/// Same as `included_files` for a source that was read from `path` or is about to be written there.
+pub fn source_included_files(source: &str, path: &Path) -> Vec<PathBuf> {
+    let sources = Sources::new(source, Some(path));
+    sources.files.into_iter().skip(1).filter_map(|f| f.path).chain(sources.missing).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn config() -> Config {
+        Config::try_from(json!({
+            "opcodes": [{"name": "OPCODE", "length": 4, "values": {"ADD": "0b0001"}}],
+            "counter_bit_length": 3,
+            "flags": ["Z", "C"],
+            "microcodes": {"PC_OUT": 0, "PC_INC": 1, "A_IN": 2, "B_IN": 3}
+        })).unwrap()
+    }
+
+    /// Counter bits and microcode columns of every operation.
+    fn operations(source: &str) -> Vec<String> {
+        let instructions = parse_instructions(source, &config()).unwrap();
+        instructions.iter().flat_map(|f| &f.operations).map(|f| format!("{} {:?}", state_vec_to_string(&f.counter), f.micro_operations)).collect()
+    }
+
+    /// Position and message of every error.
+    fn errors(source: &str) -> Vec<String> {
+        let SourceErrors(errors) = parse_instructions(source, &config()).err().unwrap();
+        errors.iter().map(|f| format!("{}:{} {}", f.line, f.column, f.error)).collect()
+    }
+
+    #[test]
+    fn macro_parameters_reach_nested_calls() {
+        let source = "#macro MOVE(from, to)\n+ $from\n+ @LATCH($to)\n#end\n#macro LATCH(register)\n+ $register PC_INC\n#end\n[OPCODE=0001]\n2 @MOVE(PC_OUT, A_IN)\n";
+        assert_eq!(operations(source), ["010 [0]", "011 [2, 1]"]);
+    }
+
+    #[test]
+    fn macro_body_moves_to_the_call() {
+        let source = "#macro M\n0 PC_OUT\n2 PC_INC\n#end\n[OPCODE=0001]\n3 @M\n";
+        assert_eq!(operations(source), ["011 [0]", "101 [1]"]);
+    }
+
+    #[test]
+    fn macro_call_errors() {
+        let source = "#macro A\n0 @B\n#end\n#macro B\n0 @A\n#end\n[OPCODE=0001]\n0 @A\n";
+        assert_eq!(errors(source), ["5:4 Macro 'A' calls itself"]);
+        let source = "#macro M(a)\n0 $a\n#end\n[OPCODE=0001]\n0 @M(PC_OUT, A_IN)\n";
+        assert_eq!(errors(source), ["5:4 Macro 'M' takes 1 arguments"]);
+    }
+
+    #[test]
+    fn counter_overflow_in_a_macro_is_reported_at_the_call() {
+        let source = "#macro M\n0 PC_OUT\n4 PC_INC\n#end\n[OPCODE=0001]\n5 @M\n";
+        assert_eq!(errors(source), ["6:1 Instruction [OPCODE=0001] ran out of counter space"]);
     }
 
-    while input.len() > 0 {
-        let (output, instruction) = parse_instruction(&input, &config)?;
-        input = output;
-        instructions.push(instruction);
-        if let Ok((rest, _)) = parse_multispace(&input) {
-            input = rest.to_owned();
+    #[test]
+    fn ranges_split_into_aligned_blocks() {
+        let patterns: Vec<String> = range_patterns(3, 6, 3).iter().map(|f| state_vec_to_string(f)).collect();
+        assert_eq!(patterns, ["011", "10#", "110"]);
+        let patterns: Vec<String> = range_patterns(0, 7, 3).iter().map(|f| state_vec_to_string(f)).collect();
+        assert_eq!(patterns, ["###"]);
+    }
+
+    #[test]
+    fn counter_forms() {
+        assert_eq!(operations("[OPCODE=0001]\n* PC_OUT\n"), ["### [0]"]);
+        assert_eq!(operations("[OPCODE=0001]\n1-4 PC_OUT\n"), ["001 [0]", "01# [0]", "100 [0]"]);
+        // A counter pattern starts with `#` but isn't a directive.
+        assert_eq!(operations("[OPCODE=0001]\n#01 PC_OUT\n"), ["#01 [0]"]);
+    }
+
+    #[test]
+    fn counter_patterns_move_with_the_macro() {
+        let source = "#macro M\n#0# PC_OUT\n#end\n[OPCODE=0001]\n1 @M\n";
+        assert_eq!(operations(source), ["001 [0]", "010 [0]", "101 [0]", "110 [0]"]);
+    }
+
+    #[test]
+    fn implicit_counters_follow_the_previous_step() {
+        assert_eq!(operations("[OPCODE=0001]\n+ PC_OUT\n4 PC_INC\n_ A_IN\n"), ["000 [0]", "100 [1]", "101 [2]"]);
+        let source = "#macro M\n0 PC_OUT\n2 PC_INC\n#end\n[OPCODE=0001]\n1 @M\n+ A_IN\n";
+        assert_eq!(operations(source), ["001 [0]", "011 [1]", "100 [2]"]);
+    }
+
+    #[test]
+    fn counter_overflow_names_the_header_as_written() {
+        assert_eq!(errors("[OPCODE=ADD]\n7 PC_OUT\n+ PC_INC\n"), ["3:1 Instruction [OPCODE=ADD] ran out of counter space"]);
+    }
+
+    /// Writes `files` into a new directory under the temp directory.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("vcb_mips_tools_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for (path, text) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
         }
+        directory
+    }
+
+    /// Parses `main.mc` in `directory`, errors are given with their path relative to it.
+    fn parse_main(directory: &Path) -> Result<Vec<String>, Vec<String>> {
+        let path = directory.join("main.mc");
+        let instructions = parse_source(&fs::read_to_string(&path).unwrap(), Some(&path), &config()).map_err(|SourceErrors(errors)| {
+            errors.iter().map(|f| {
+                let path = f.path.as_ref().unwrap().strip_prefix(directory).unwrap();
+                format!("{}:{}:{} {}", path.display(), f.line, f.column, f.error)
+            }).collect::<Vec<String>>()
+        })?;
+        Ok(instructions.iter().flat_map(|f| &f.operations).map(|f| format!("{} {:?}", state_vec_to_string(&f.counter), f.micro_operations)).collect())
+    }
+
+    #[test]
+    fn includes_resolve_relative_to_the_including_file() {
+        let directory = write_files("relative", &[
+            ("main.mc", "#include \"lib/a.mc\"\n[OPCODE=0001]\n0 @A\n"),
+            ("lib/a.mc", "#include \"b.mc\"\n#macro A\n+ @B\n#end\n"),
+            ("lib/b.mc", "#macro B\n+ PC_OUT\n#end\n")
+        ]);
+        assert_eq!(parse_main(&directory).unwrap(), ["000 [0]"]);
+        fs::remove_dir_all(directory).unwrap();
     }
-    Ok(instructions)
-}
\ No newline at end of file
+
+    #[test]
+    fn include_cycle_is_reported_where_it_closes() {
+        let directory = write_files("cycle", &[
+            ("main.mc", "#include \"lib/a.mc\"\n"),
+            ("lib/a.mc", "#include \"b.mc\"\n"),
+            ("lib/b.mc", "\n#include \"a.mc\"\n")
+        ]);
+        assert_eq!(parse_main(&directory).unwrap_err(), ["lib/b.mc:2:11 Including 'a.mc' forms a cycle"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_included_twice_is_parsed_once() {
+        let directory = write_files("diamond", &[
+            ("main.mc", "#include \"a.mc\"\n#include \"b.mc\"\n[OPCODE=0001]\n0 @C\n"),
+            ("a.mc", "#include \"common.mc\"\n"),
+            ("b.mc", "#include \"common.mc\"\n"),
+            ("common.mc", "#macro C\n0 PC_OUT\n#end\n")
+        ]);
+        assert_eq!(parse_main(&directory).unwrap(), ["000 [0]"]);
+        assert_eq!(included_files(&directory.join("main.mc")).len(), 3);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_included_file() {
+        let directory = write_files("error_path", &[
+            ("main.mc", "#include \"lib/a.mc\"\n[OPCODE=0001]\n0 @A\n"),
+            ("lib/a.mc", "#macro A\n+ FOO\n#end\n#include \"missing.mc\"\n")
+        ]);
+        let errors = parse_main(&directory).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "lib/a.mc:2:3 Instruction 'FOO' doesn't exist");
+        assert!(errors[1].starts_with("lib/a.mc:4:11 Couldn't read included file 'missing.mc'"), "{}", errors[1]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Bits of an opcode literal, or the error message.
+    fn literal(text: &str, length: u64) -> Result<String, String> {
+        parse_opcode_literal(text, "OPCODE", length).map(|f| state_vec_to_string(&f)).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn opcode_literals() {
+        assert_eq!(literal("0x#", 6), Ok("00####".to_owned()));
+        assert_eq!(literal("0b1x0", 3), Ok("1#0".to_owned()));
+        assert_eq!(literal("0x0f", 4), Ok("1111".to_owned()));
+        assert_eq!(literal("0x#3", 4), Ok("0011".to_owned()));
+        assert_eq!(literal("0x13", 4), Err("Opcode 'OPCODE' has invalid length".to_owned()));
+        assert_eq!(literal("01#", 3), Ok("01#".to_owned()));
+        assert_eq!(literal("01#", 4), Err("Opcode 'OPCODE' has invalid length".to_owned()));
+    }
+
+    #[test]
+    fn opcode_values_come_from_the_config_then_constants_then_literals() {
+        let source = "#const ADD = 0b0010\n#const SUB = 0b0011\n[OPCODE=ADD]\n0 PC_OUT\n[OPCODE=SUB]\n0 PC_OUT\n[OPCODE=0x4]\n0 PC_OUT\n";
+        let instructions = parse_instructions(source, &config()).unwrap();
+        let opcodes: Vec<String> = instructions.iter().map(|f| state_vec_to_string(&f.opcodes[0])).collect();
+        assert_eq!(opcodes, ["0001", "0011", "0100"]);
+        assert_eq!(errors("[OPCODE=MUL]\n0 PC_OUT\n"), ["1:9 Constant or opcode value 'MUL' doesn't exist"]);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{error::ParseError, microcode::{Instruction, Operation, State}, Config};
+
+/// One AND/NOR row pair of the PLA, `inputs` are the opcode, counter and flag bits in layout order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub inputs: Vec<State>,
+    pub micro_operations: Vec<u64>,
+    pub instruction: usize
+}
+
+impl Term {
+    /// Whether every input matched by `other` is also matched by `self`.
+    pub fn covers(&self, other: &Term) -> bool {
+        self.inputs.iter().zip(&other.inputs).all(|(a, b)| *a == State::Any || a == b)
+    }
+
+    /// Whether the row fires for the given input bits. The AND row reads the bit column for `True` and the inverted column for `False`
+    /// and fires when every read column is high, the NOR row reads the opposite columns and fires when none is high, both match the same inputs.
+    pub fn matches(&self, bits: &[bool]) -> bool {
+        self.inputs.iter().zip(bits).all(|(state, bit)| match state {
+            State::True => *bit,
+            State::False => !*bit,
+            State::Any => true
+        })
+    }
+
+    fn merge(&self, other: &Term) -> Option<Term> {
+        let mut difference = None;
+        for (i, (a, b)) in self.inputs.iter().zip(&other.inputs).enumerate() {
+            if a == b {continue;}
+            if *a == State::Any || *b == State::Any || difference.is_some() {return None;}
+            difference = Some(i);
+        }
+        let mut inputs = self.inputs.clone();
+        inputs[difference?] = State::Any;
+        Some(Term { inputs, micro_operations: self.micro_operations.clone(), instruction: self.instruction.min(other.instruction) })
+    }
+}
+
+/// Flattens the instructions into rows, operations built by hand must have `counter_bit_length` counter bits.
+pub fn instructions_to_terms(instructions: &[Instruction], config: &Config) -> Result<Vec<Term>, ParseError> {
+    let mut terms = vec![];
+    for (instruction_index, instruction) in instructions.iter().enumerate() {
+        for operation in &instruction.operations {
+            let mut inputs: Vec<State> = instruction.opcodes.iter().flatten().copied().collect();
+            if operation.counter.len() as u64 != config.counter_bit_length {return Err(ParseError::CounterFormatting);}
+            inputs.extend_from_slice(&operation.counter);
+            inputs.extend_from_slice(&operation.flags);
+
+            let mut micro_operations = operation.micro_operations.clone();
+            micro_operations.sort();
+            micro_operations.dedup();
+            terms.push(Term { inputs, micro_operations, instruction: instruction_index });
+        }
+    }
+    Ok(terms)
+}
+
+/// Groups terms into instructions by their opcode bits, in the order the opcode patterns first appear.
+pub fn terms_to_instructions(terms: &[Term], config: &Config) -> Vec<Instruction> {
+    let opcodes_length = config.opcodes.iter().map(|f| f.1).sum::<u64>() as usize;
+    let counter_end = opcodes_length + config.counter_bit_length as usize;
+    let mut instructions: Vec<Instruction> = vec![];
+    for term in terms {
+        let mut opcodes = vec![];
+        let mut offset = 0;
+        for (_, length) in &config.opcodes {
+            opcodes.push(term.inputs[offset..offset + *length as usize].to_vec());
+            offset += *length as usize;
+        }
+        let operation = Operation {
+            counter: term.inputs[opcodes_length..counter_end].to_vec(),
+            micro_operations: term.micro_operations.clone(),
+            flags: term.inputs[counter_end..].to_vec()
+        };
+        match instructions.iter_mut().find(|f| f.opcodes == opcodes) {
+            Some(instruction) => instruction.operations.push(operation),
+            None => instructions.push(Instruction { opcodes, operations: vec![operation] })
+        }
+    }
+    instructions
+}
+
+fn merge_identical_inputs(terms: Vec<Term>) -> Vec<Term> {
+    let mut result: Vec<Term> = vec![];
+    for term in terms {
+        if let Some(existing) = result.iter_mut().find(|f| f.inputs == term.inputs) {
+            existing.micro_operations.extend(term.micro_operations);
+            existing.micro_operations.sort();
+            existing.micro_operations.dedup();
+            existing.instruction = existing.instruction.min(term.instruction);
+        } else {
+            result.push(term);
+        }
+    }
+    result
+}
+
+fn merge_adjacent(mut terms: Vec<Term>) -> Vec<Term> {
+    let mut i = 0;
+    while i < terms.len() {
+        let mut j = i + 1;
+        while j < terms.len() {
+            if terms[i].micro_operations == terms[j].micro_operations {
+                if let Some(merged) = terms[i].merge(&terms[j]) {
+                    terms[i] = merged;
+                    terms.remove(j);
+                    j = i + 1;
+                    continue;
+                }
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    terms
+}
+
+fn absorb(terms: Vec<Term>) -> Vec<Term> {
+    let absorbed = |index: usize, term: &Term| terms.iter().enumerate().any(|(other_index, other)| {
+        other_index != index
+            && other.covers(term)
+            && term.micro_operations.iter().all(|f| other.micro_operations.contains(f))
+            && (other_index < index || !term.covers(other) || other.micro_operations != term.micro_operations)
+    });
+    terms.iter().enumerate()
+        .filter(|(index, term)| !absorbed(*index, term))
+        .map(|(_, term)| term.clone())
+        .collect()
+}
+
+/// Merges terms with the same outputs that differ in a single input bit and drops terms already covered by another one,
+/// repeated until nothing changes. The resulting PLA computes the same outputs for every input.
+pub fn minimize(mut terms: Vec<Term>) -> Vec<Term> {
+    terms.retain(|f| !f.micro_operations.is_empty());
+    merge_terms(terms)
+}
+
+/// Same as `minimize` but keeps terms that write nothing, for when the terms only describe a set of inputs.
+pub fn merge_terms(mut terms: Vec<Term>) -> Vec<Term> {
+    loop {
+        let length = terms.len();
+        terms = absorb(merge_adjacent(merge_identical_inputs(terms)));
+        if terms.len() == length {break;}
+    }
+    terms
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::{microcode::str_to_state_vec, rom::rom_words};
+    use super::*;
+
+    fn term(inputs: &str, micro_operations: &[u64]) -> Term {
+        Term { inputs: str_to_state_vec(inputs).unwrap(), micro_operations: micro_operations.to_vec(), instruction: 0 }
+    }
+
+    #[test]
+    fn minimize_keeps_the_truth_table() {
+        let config = Config::try_from(json!({
+            "opcodes": [{"name": "OPCODE", "length": 2}],
+            "counter_bit_length": 2,
+            "flags_bit_length": 1,
+            "microcodes": {"A": 0, "B": 1, "C": 2, "D": 3}
+        })).unwrap();
+        let cases = [
+            // identical inputs with different outputs
+            (vec![term("0101#", &[0]), term("0101#", &[1]), term("0101#", &[0, 1])], 1),
+            // a term covered by a wider one with the same outputs, and one that isn't
+            (vec![term("01###", &[2]), term("0110#", &[2]), term("0110#", &[2, 3]), term("011#0", &[3])], 3),
+            // neighbours merging into one term, one that writes nothing
+            (vec![term("00000", &[3]), term("00001", &[3]), term("00010", &[3]), term("00011", &[3]), term("11111", &[])], 1),
+            // overlapping terms with different outputs
+            (vec![term("1####", &[0]), term("1#1##", &[1]), term("1#10#", &[0, 1]), term("0##1#", &[1])], 4)
+        ];
+        for (terms, expected_length) in cases {
+            let minimized = minimize(terms.clone());
+            assert_eq!(rom_words(&terms, &config).unwrap(), rom_words(&minimized, &config).unwrap(), "{:?}", minimized);
+            assert_eq!(minimized.len(), expected_length, "{:?}", minimized);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{error::{Error, RomError}, microcode::State, pla::Term, Config};
+
+/// Address bits past which an image is refused, the words alone would take hundreds of megabytes.
+const MAX_ADDRESS_WIDTH: u64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomFormat {
+    /// Words back to back, big endian, each padded to whole bytes.
+    Binary,
+    /// The binary image as Intel HEX records.
+    IntelHex,
+    /// Logisim `v2.0 raw` memory image.
+    Logisim,
+    /// 32-bit little endian words, the word size of VCB's VMEM.
+    Vmem
+}
+
+/// Output columns in a ROM word, bit `i` of a word is column `i`.
+pub fn word_width(config: &Config) -> u64 {
+    config.max_micro_operation_index().map_or(0, |f| f + 1)
+}
+
+/// The PLA as a truth table. The address is the opcode, counter and flag bits in layout order with the first opcode bit as
+/// the most significant, the word has a bit set for every column written by a matching row.
+pub fn rom_words(terms: &[Term], config: &Config) -> Result<Vec<u64>, Error> {
+    let address_width = config.opcodes.iter().map(|f| f.1).sum::<u64>() + config.counter_bit_length + config.flags_bit_length;
+    if address_width > MAX_ADDRESS_WIDTH {return Err(RomError::AddressWidth(address_width).into());}
+    if word_width(config) > 64 {return Err(RomError::WordWidth(word_width(config), 64).into());}
+
+    let mut words = vec![0u64; 1 << address_width];
+    for term in terms {
+        let word: u64 = term.micro_operations.iter().map(|f| 1u64 << f).fold(0, |a, b| a | b);
+        let mut base = 0;
+        let mut any_bits = vec![];
+        for (i, state) in term.inputs.iter().enumerate() {
+            let bit = address_width as usize - 1 - i;
+            match state {
+                State::True => base |= 1 << bit,
+                State::False => {},
+                State::Any => any_bits.push(bit)
+            }
+        }
+        // every combination of the don't-care bits
+        for combination in 0..1usize << any_bits.len() {
+            let address = any_bits.iter().enumerate().fold(base, |address, (i, bit)| address | (combination >> i & 1) << bit);
+            words[address] |= word;
+        }
+    }
+    Ok(words)
+}
+
+/// Encodes the words of `rom_words` in the given format.
+pub fn generate_rom(words: &[u64], config: &Config, format: RomFormat) -> Result<Vec<u8>, Error> {
+    let width = word_width(config);
+    let word_bytes = width.div_ceil(8).max(1) as usize;
+    match format {
+        RomFormat::Binary => Ok(to_binary(words, word_bytes)),
+        RomFormat::IntelHex => Ok(to_intel_hex(&to_binary(words, word_bytes)).into_bytes()),
+        RomFormat::Logisim => Ok(to_logisim(words, width).into_bytes()),
+        RomFormat::Vmem => {
+            if width > 32 {return Err(RomError::WordWidth(width, 32).into());}
+            Ok(words.iter().flat_map(|f| (*f as u32).to_le_bytes()).collect())
+        }
+    }
+}
+
+fn to_binary(words: &[u64], word_bytes: usize) -> Vec<u8> {
+    words.iter().flat_map(|f| f.to_be_bytes()[8 - word_bytes..].to_vec()).collect()
+}
+
+fn intel_hex_record(address: u16, record_type: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)).wrapping_neg();
+    bytes.push(checksum);
+    format!(":{}\n", bytes.iter().map(|f| format!("{:02X}", f)).collect::<String>())
+}
+
+/// 16 data bytes per record, with an extended linear address record before every 64 KiB block.
+fn to_intel_hex(data: &[u8]) -> String {
+    let mut hex = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let address = i * 16;
+        if address % 0x10000 == 0 && address > 0 {
+            hex.push_str(&intel_hex_record(0, 4, &((address >> 16) as u16).to_be_bytes()));
+        }
+        hex.push_str(&intel_hex_record(address as u16, 0, chunk));
+    }
+    hex.push_str(&intel_hex_record(0, 1, &[]));
+    hex
+}
+
+/// Eight words per line, runs of the same word are written once as `count*word`.
+fn to_logisim(words: &[u64], width: u64) -> String {
+    let digits = width.div_ceil(4).max(1) as usize;
+    let mut values = vec![];
+    for run in words.chunk_by(|a, b| a == b) {
+        if run.len() > 1 {
+            values.push(format!("{}*{:0digits$x}", run.len(), run[0]));
+        } else {
+            values.push(format!("{:0digits$x}", run[0]));
+        }
+    }
+    let lines: Vec<String> = values.chunks(8).map(|f| f.join(" ")).collect();
+    format!("v2.0 raw\n{}\n", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn intel_hex_checksums() {
+        assert_eq!(intel_hex_record(0x30, 0, &[0x02, 0x33, 0x7A]), ":0300300002337A1E\n");
+        assert_eq!(to_intel_hex(&[0xFF; 2]), ":02000000FFFF00\n:00000001FF\n");
+    }
+
+    #[test]
+    fn intel_hex_over_64_kib_has_extended_addresses() {
+        let hex = to_intel_hex(&vec![0; 0x10010]);
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines.len(), 0x1001 + 2);
+        assert_eq!(lines[0xFFF], ":10FFF0000000000000000000000000000000000001");
+        assert_eq!(lines[0x1000], ":020000040001F9");
+        assert_eq!(lines[0x1001], ":1000000000000000000000000000000000000000F0");
+        assert_eq!(lines[0x1002], ":00000001FF");
+    }
+
+    #[test]
+    fn logisim_runs() {
+        assert_eq!(to_logisim(&[0, 0, 0, 5, 1, 1], 4), "v2.0 raw\n3*0 5 2*1\n");
+        assert_eq!(to_logisim(&[0x1, 0x2], 12), "v2.0 raw\n001 002\n");
+    }
+
+    #[test]
+    fn vmem_words_fit_in_32_bits() {
+        let config = Config::try_from(json!({
+            "opcodes": [{"name": "OPCODE", "length": 1}],
+            "counter_bit_length": 1,
+            "flags_bit_length": 0,
+            "microcodes": {"LOW": 0, "HIGH": 32}
+        })).unwrap();
+        let error = generate_rom(&[0; 4], &config, RomFormat::Vmem).unwrap_err();
+        assert_eq!(error.to_string(), "Microcode word is 33 bits wide, the ROM format holds at most 32");
+        assert_eq!(generate_rom(&[1 << 32 | 1], &config, RomFormat::Binary).unwrap(), [1, 0, 0, 0, 1]);
+    }
+}
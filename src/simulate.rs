@@ -0,0 +1,80 @@
+use crate::{error::ParseError, microcode::{Instruction, State, str_to_state_vec, parse_opcode_literal}, pla::instructions_to_terms, Config};
+
+fn parse_bits(input: &str, length: u64, name: &str) -> Result<Vec<bool>, ParseError> {
+    let state_vec = str_to_state_vec(input)?;
+    if state_vec.len() as u64 != length {return Err(ParseError::OpcodeLength(name.to_owned()));}
+    state_vec_to_bits(&state_vec)
+}
+
+fn state_vec_to_bits(state_vec: &[State]) -> Result<Vec<bool>, ParseError> {
+    state_vec.iter().map(|f| match f {
+        State::True => Ok(true),
+        State::False => Ok(false),
+        State::Any => Err(ParseError::Formatting)
+    }).collect()
+}
+
+/// Opcode bits in layout order from `NAME=VALUE` pairs, the value is a literal without don't-cares or a config value name.
+/// Opcodes that aren't given are all zeros.
+pub fn parse_opcode_values(values: &[String], config: &Config) -> Result<Vec<bool>, ParseError> {
+    for value in values {
+        let name = value.split_once('=').ok_or(ParseError::Formatting)?.0.trim();
+        if !config.opcodes.iter().any(|f| f.0 == name) {return Err(ParseError::MissingOpcode(name.to_owned()));}
+    }
+
+    let mut bits = vec![];
+    for (name, length) in &config.opcodes {
+        let value = values.iter()
+            .filter_map(|f| f.split_once('='))
+            .find(|(key, _)| key.trim() == name)
+            .map(|(_, value)| value.trim());
+        match value {
+            Some(value) => {
+                let state_vec = match config.opcode_values.get(name).and_then(|f| f.get(value)) {
+                    Some(state_vec) => state_vec.clone(),
+                    None => parse_opcode_literal(value, name, *length)?
+                };
+                bits.append(&mut state_vec_to_bits(&state_vec)?);
+            },
+            None => bits.append(&mut vec![false; *length as usize])
+        }
+    }
+    Ok(bits)
+}
+
+/// Flag bits either as one bit string or as a comma separated list of `NAME=BIT` for named flags, flags that aren't given are clear.
+pub fn parse_flag_values(value: Option<&str>, config: &Config) -> Result<Vec<bool>, ParseError> {
+    let mut bits = vec![false; config.flags_bit_length as usize];
+    match value {
+        Some(value) if value.contains('=') => {
+            for pair in value.split(',') {
+                let (name, bit) = pair.split_once('=').ok_or(ParseError::Formatting)?;
+                let index = config.flags.iter().position(|f| f == name.trim()).ok_or(ParseError::MissingFlag(name.trim().to_owned()))?;
+                bits[index] = parse_bits(bit.trim(), 1, name.trim())?[0];
+            }
+            Ok(bits)
+        },
+        Some(value) => parse_bits(value, config.flags_bit_length, "flags"),
+        None => Ok(bits)
+    }
+}
+
+/// Microcodes written on every counter tick, evaluated row by row like the generated PLA.
+pub fn simulate(instructions: &[Instruction], config: &Config, opcode: &[bool], flags: &[bool]) -> Result<Vec<Vec<u64>>, ParseError> {
+    let terms = instructions_to_terms(instructions, config)?;
+    let mut ticks = vec![];
+    for counter in 0..2u64.pow(config.counter_bit_length as u32) {
+        let mut bits = opcode.to_vec();
+        bits.extend((0..config.counter_bit_length).rev().map(|i| counter >> i & 1 == 1));
+        bits.extend_from_slice(flags);
+
+        let mut micro_operations: Vec<u64> = terms.iter()
+            .filter(|f| f.matches(&bits))
+            .flat_map(|f| f.micro_operations.iter().copied())
+            .collect();
+        micro_operations.sort();
+        micro_operations.dedup();
+        ticks.push(micro_operations);
+    }
+    Ok(ticks)
+}
@@ -0,0 +1,185 @@
+use crate::{analysis::format_inputs, error::{Error, RomError}, microcode::State, pla::Term, rom::{rom_words, word_width}, Config, Field};
+
+/// Verilog-2005 keywords.
+const KEYWORDS: &[&str] = &[
+    "always", "and", "assign", "automatic", "begin", "buf", "bufif0", "bufif1", "case", "casex", "casez", "cell", "cmos", "config",
+    "deassign", "default", "defparam", "design", "disable", "edge", "else", "end", "endcase", "endconfig", "endfunction", "endgenerate",
+    "endmodule", "endprimitive", "endspecify", "endtable", "endtask", "event", "for", "force", "forever", "fork", "function", "generate",
+    "genvar", "highz0", "highz1", "if", "ifnone", "incdir", "include", "initial", "inout", "input", "instance", "integer", "join", "large",
+    "liblist", "library", "localparam", "macromodule", "medium", "module", "nand", "negedge", "nmos", "nor", "noshowcancelled", "not",
+    "notif0", "notif1", "or", "output", "parameter", "pmos", "posedge", "primitive", "pull0", "pull1", "pulldown", "pullup",
+    "pulsestyle_ondetect", "pulsestyle_onevent", "rcmos", "real", "realtime", "reg", "release", "repeat", "rnmos", "rpmos", "rtran",
+    "rtranif0", "rtranif1", "scalared", "showcancelled", "signed", "small", "specify", "specparam", "strong0", "strong1", "supply0",
+    "supply1", "table", "task", "time", "tran", "tranif0", "tranif1", "tri", "tri0", "tri1", "triand", "trior", "trireg", "unsigned",
+    "use", "uwire", "vectored", "wait", "wand", "weak0", "weak1", "while", "wire", "wor", "xnor", "xor"
+];
+
+/// Names the module declares itself, outputs can't use them.
+const GENERATED_NAMES: &[&str] = &["opcode", "counter", "flags", "control", "inputs"];
+
+/// Replaces characters Verilog doesn't allow in identifiers, names starting with a digit get a leading underscore and keywords a trailing one.
+fn identifier(name: &str) -> String {
+    let name: String = name.chars().map(|f| if f.is_ascii_alphanumeric() || f == '_' { f } else { '_' }).collect();
+    let name = if name.starts_with(|f: char| f.is_ascii_digit()) { format!("_{}", name) } else { name };
+    if KEYWORDS.contains(&name.as_str()) { format!("{}_", name) } else { name }
+}
+
+/// Identifiers for the outputs in order, a name that's already taken by a generated name or an earlier output gets a numbered suffix.
+fn output_identifiers<'a>(names: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut used: Vec<String> = GENERATED_NAMES.iter().map(|f| f.to_string()).collect();
+    names.map(|name| {
+        let base = identifier(name);
+        let unique = (1..).map(|i| if i == 1 { base.clone() } else { format!("{}_{}", base, i) }).find(|f| !used.contains(f)).unwrap();
+        used.push(unique.clone());
+        unique
+    }).collect()
+}
+
+/// Comment naming the config entry when its identifier differs.
+fn original_name(name: &str, identifier: &str) -> String {
+    if name == identifier { String::new() } else { format!(" '{}'", name) }
+}
+
+fn bits_literal(bits: impl Iterator<Item = bool>) -> String {
+    let bits: String = bits.map(|f| if f { '1' } else { '0' }).collect();
+    format!("{}'b{}", bits.len(), bits)
+}
+
+fn input_widths(config: &Config) -> (u64, u64, u64) {
+    (config.opcodes.iter().map(|f| f.1).sum(), config.counter_bit_length, config.flags_bit_length)
+}
+
+/// Ports of the module, the flags are left out if there are none.
+fn ports(config: &Config) -> Vec<(&'static str, u64)> {
+    let (opcode_width, counter_width, flags_width) = input_widths(config);
+    let ports = [("opcode", opcode_width), ("counter", counter_width), ("flags", flags_width)];
+    ports.into_iter().filter(|f| f.1 > 0).collect()
+}
+
+/// Combinational module with `opcode`, `counter` and `flags` inputs in layout order and a `control` word with bit `i` for column `i`.
+/// Every row of the PLA is an `if` on the masked inputs, the words of all matching rows are ORed like in the PLA.
+/// Each microcode and field also gets an output of its own.
+pub fn generate_verilog(terms: &[Term], config: &Config, module_name: &str) -> String {
+    let width = word_width(config).max(1);
+    let ports = ports(config);
+    let input_width: u64 = ports.iter().map(|f| f.1).sum();
+
+    let mut microcodes: Vec<(&String, u64)> = config.microcode_map.iter().filter(|f| *f.1 >= 0).map(|(name, index)| (name, *index as u64)).collect();
+    microcodes.sort_by_key(|f| (f.1, f.0.clone()));
+    let fields: Vec<&Field> = config.fields.iter().filter(|f| f.length > 0).collect();
+    let identifiers = output_identifiers(microcodes.iter().map(|f| f.0.as_str()).chain(fields.iter().map(|f| f.name.as_str())));
+    let (microcode_identifiers, field_identifiers) = identifiers.split_at(microcodes.len());
+
+    let mut lines = vec![format!("module {} (", identifier(module_name))];
+    let mut declarations: Vec<String> = ports.iter().map(|(name, length)| format!("    input wire [{}:0] {}", length - 1, name)).collect();
+    declarations.push(format!("    output reg [{}:0] control", width - 1));
+    for ((name, index), identifier) in microcodes.iter().zip(microcode_identifiers) {
+        declarations.push(format!("    output wire {} /* column {}{} */", identifier, index, original_name(name, identifier)));
+    }
+    for (field, identifier) in fields.iter().zip(field_identifiers) {
+        let comment = if field.name == *identifier { String::new() } else { format!(" /*{} */", original_name(&field.name, identifier)) };
+        declarations.push(format!("    output wire [{}:0] {}{}", field.length - 1, identifier, comment));
+    }
+    lines.push(declarations.join(",\n"));
+    lines.push(");".to_owned());
+
+    for ((_, index), identifier) in microcodes.iter().zip(microcode_identifiers) {
+        lines.push(format!("    assign {} = control[{}];", identifier, index));
+    }
+    for (field, identifier) in fields.iter().zip(field_identifiers) {
+        lines.push(format!("    assign {} = control[{}:{}];", identifier, field.index + field.length - 1, field.index));
+    }
+
+    let names: Vec<&str> = ports.iter().map(|f| f.0).collect();
+    lines.push(String::new());
+    lines.push(format!("    wire [{}:0] inputs = {{{}}};", input_width.max(1) - 1, names.join(", ")));
+    lines.push(String::new());
+    lines.push("    always @* begin".to_owned());
+    lines.push(format!("        control = {{{}{{1'b0}}}};", width));
+    for term in terms.iter().filter(|f| !f.micro_operations.is_empty()) {
+        let mask = bits_literal(term.inputs.iter().map(|f| *f != State::Any));
+        let value = bits_literal(term.inputs.iter().map(|f| *f == State::True));
+        let word = bits_literal((0..width).rev().map(|f| term.micro_operations.contains(&f)));
+        lines.push(format!("        // {}", format_inputs(&term.inputs, config)));
+        lines.push(format!("        if ((inputs & {}) == {}) control = control | {};", mask, value, word));
+    }
+    lines.push("    end".to_owned());
+    lines.push("endmodule".to_owned());
+    lines.join("\n") + "\n"
+}
+
+/// Testbench driving every input combination into the module and comparing `control` with the ROM truth table, which it
+/// reads from `words_path` with `$readmemh`. Returns the testbench and the contents of that file, one hex word per address.
+pub fn generate_testbench(terms: &[Term], config: &Config, module_name: &str, words_path: &str) -> Result<(String, String), Error> {
+    let ports = ports(config);
+    if ports.is_empty() {return Err(RomError::NoInputs.into());}
+    let words = rom_words(terms, config)?;
+    let width = word_width(config).max(1);
+    let module_name = identifier(module_name);
+    let names: Vec<&str> = ports.iter().map(|f| f.0).collect();
+    let words_path = words_path.replace('\\', "\\\\").replace('"', "\\\"");
+
+    let mut lines = vec![format!("module {}_tb;", module_name)];
+    for (name, length) in &ports {
+        lines.push(format!("    reg [{}:0] {};", length - 1, name));
+    }
+    lines.push(format!("    wire [{}:0] control;", width - 1));
+    lines.push(format!("    reg [{}:0] expected [0:{}];", width - 1, words.len() - 1));
+    lines.push("    integer address;".to_owned());
+    lines.push("    integer errors = 0;".to_owned());
+    lines.push(String::new());
+    let connections: Vec<String> = names.iter().chain(&["control"]).map(|f| format!(".{}({})", f, f)).collect();
+    lines.push(format!("    {} dut ({});", module_name, connections.join(", ")));
+    lines.push(String::new());
+    lines.push("    initial begin".to_owned());
+    lines.push(format!("        $readmemh(\"{}\", expected);", words_path));
+    lines.push(format!("        for (address = 0; address < {}; address = address + 1) begin", words.len()));
+    lines.push(format!("            {{{}}} = address;", names.join(", ")));
+    lines.push("            #1;".to_owned());
+    lines.push("            if (control !== expected[address]) begin".to_owned());
+    lines.push("                $display(\"FAIL %h: control %h, expected %h\", address, control, expected[address]);".to_owned());
+    lines.push("                errors = errors + 1;".to_owned());
+    lines.push("            end".to_owned());
+    lines.push("        end".to_owned());
+    lines.push("        if (errors == 0) $display(\"PASS\");".to_owned());
+    lines.push("        else $display(\"%0d mismatches\", errors);".to_owned());
+    lines.push("        $finish;".to_owned());
+    lines.push("    end".to_owned());
+    lines.push("endmodule".to_owned());
+
+    let digits = width.div_ceil(4) as usize;
+    let hex: Vec<String> = words.iter().map(|f| format!("{:0digits$x}", f)).collect();
+    Ok((lines.join("\n") + "\n", hex.join("\n") + "\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::microcode::str_to_state_vec;
+    use super::*;
+
+    fn config(opcode_length: u64) -> Config {
+        let opcodes = if opcode_length == 0 { json!([]) } else { json!([{"name": "OPCODE", "length": opcode_length}]) };
+        Config::try_from(json!({
+            "opcodes": opcodes,
+            "counter_bit_length": 0,
+            "flags_bit_length": 0,
+            "microcodes": {"A": 0, "B": 5}
+        })).unwrap()
+    }
+
+    #[test]
+    fn testbench_reads_the_words_from_a_file() {
+        let terms = [Term { inputs: str_to_state_vec("1#").unwrap(), micro_operations: vec![0, 5], instruction: 0 }];
+        let (testbench, words) = generate_testbench(&terms, &config(2), "cpu", "out\\cpu \"tb\".hex").unwrap();
+        assert!(testbench.contains("$readmemh(\"out\\\\cpu \\\"tb\\\".hex\", expected);"), "{}", testbench);
+        assert!(testbench.contains("for (address = 0; address < 4; address = address + 1) begin"), "{}", testbench);
+        assert_eq!(words, "00\n00\n21\n21\n");
+    }
+
+    #[test]
+    fn testbench_needs_inputs() {
+        let error = generate_testbench(&[], &config(0), "cpu", "cpu_tb.hex").unwrap_err();
+        assert_eq!(error.to_string(), "The config has no opcode, counter or flag bits to drive the module with");
+    }
+}